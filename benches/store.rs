@@ -0,0 +1,237 @@
+//! Create/respond/poll throughput of the sharded `MemoryStore` against the
+//! evmap + `Mutex<WriteHandle>` design it replaced.
+//!
+//! Run with `cargo bench --bench store`.
+
+use chrono::prelude::Utc;
+use configgymajiggy::config::TtlPolicy;
+use configgymajiggy::seal::{Keyring, SealedResult};
+use configgymajiggy::store::{create_key, MemoryStore, PinItem, PinStore};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde_json::json;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const NAMESPACE: &str = "bench";
+/// Pins kept live for the respond and poll benchmarks.
+const RESIDENT_PINS: usize = 10_000;
+const THREADS: usize = 4;
+
+/// The operations each handler performs against the store.
+trait BenchStore: Clone + Send + 'static {
+    fn create(&self, pin: &str) -> bool;
+    fn respond(&self, pin: &str, result: &SealedResult) -> bool;
+    fn poll(&self, pin: &str) -> Option<PinItem>;
+}
+
+impl BenchStore for Arc<MemoryStore> {
+    fn create(&self, pin: &str) -> bool {
+        self.create_if_absent(NAMESPACE, PinItem::new(pin.to_string(), None, Utc::now()))
+            .unwrap()
+    }
+
+    fn respond(&self, pin: &str, result: &SealedResult) -> bool {
+        self.update(NAMESPACE, pin, &mut |item| {
+            item.touch(Utc::now(), TtlPolicy::default().max_lifetime_secs);
+            item.result = Some(result.clone());
+            true
+        })
+        .unwrap()
+        .is_some()
+    }
+
+    fn poll(&self, pin: &str) -> Option<PinItem> {
+        self.take_if_populated(NAMESPACE, pin, Utc::now()).unwrap()
+    }
+}
+
+/// evmap needs `Hash` and `ShallowCopy` on its values, which `PinItem` no longer has.
+#[derive(Clone, PartialEq, Eq)]
+struct LegacyItem(PinItem);
+
+impl Hash for LegacyItem {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.timestamp.hash(state);
+        self.0.pin.hash(state);
+    }
+}
+
+impl evmap::ShallowCopy for LegacyItem {
+    unsafe fn shallow_copy(&self) -> std::mem::ManuallyDrop<Self> {
+        std::mem::ManuallyDrop::new(self.clone())
+    }
+}
+
+/// The previous store: one evmap whose writes all serialize on a mutex and
+/// refresh after every operation. Each thread gets its own read handle, as
+/// the `unsafe impl Sync` this replaces only pretended it could share one.
+#[derive(Clone)]
+struct LegacyStore {
+    read: evmap::ReadHandle<String, LegacyItem>,
+    write: Arc<Mutex<evmap::WriteHandle<String, LegacyItem>>>,
+}
+
+impl LegacyStore {
+    fn new() -> Self {
+        let (read, write) = evmap::new();
+        LegacyStore {
+            read,
+            write: Arc::new(Mutex::new(write)),
+        }
+    }
+}
+
+impl BenchStore for LegacyStore {
+    fn create(&self, pin: &str) -> bool {
+        let key = create_key(NAMESPACE, pin);
+        if self.read.contains_key(&key) {
+            return false;
+        }
+        let mut write_handle = self.write.lock().unwrap();
+        write_handle.insert(
+            key,
+            LegacyItem(PinItem::new(pin.to_string(), None, Utc::now())),
+        );
+        write_handle.refresh();
+        true
+    }
+
+    fn respond(&self, pin: &str, result: &SealedResult) -> bool {
+        let key = create_key(NAMESPACE, pin);
+        if !self.read.contains_key(&key) {
+            return false;
+        }
+        let mut write_handle = self.write.lock().unwrap();
+        write_handle.update(
+            key,
+            LegacyItem(PinItem::new(
+                pin.to_string(),
+                Some(result.clone()),
+                Utc::now(),
+            )),
+        );
+        write_handle.refresh();
+        true
+    }
+
+    fn poll(&self, pin: &str) -> Option<PinItem> {
+        let key = create_key(NAMESPACE, pin);
+        let item = self.read.get_one(&key)?.0.clone();
+        if item.result.is_some() {
+            let mut write_handle = self.write.lock().unwrap();
+            write_handle.empty(key);
+            write_handle.refresh();
+        }
+        Some(item)
+    }
+}
+
+fn pin_for(n: usize) -> String {
+    format!("{:08X}", n)
+}
+
+/// A result as the handlers store it, sealed once up front since the
+/// benchmarks measure the stores rather than the cipher.
+fn sample_result() -> SealedResult {
+    let mut result = HashMap::new();
+    result.insert("token".to_string(), json!("0123456789abcdef"));
+    Keyring::generate().seal(NAMESPACE, "bench", &result.into()).unwrap()
+}
+
+fn bench_create<S: BenchStore>(c: &mut Criterion, name: &str, new_store: impl Fn() -> S) {
+    c.bench_function(&format!("create/{}", name), |b| {
+        b.iter_custom(|iters| {
+            let store = new_store();
+            let pins: Vec<String> = (0..iters as usize).map(pin_for).collect();
+            let start = Instant::now();
+            for pin in &pins {
+                assert!(store.create(pin));
+            }
+            start.elapsed()
+        })
+    });
+}
+
+fn bench_respond<S: BenchStore>(c: &mut Criterion, name: &str, new_store: impl Fn() -> S) {
+    let store = new_store();
+    let pins: Vec<String> = (0..RESIDENT_PINS).map(pin_for).collect();
+    for pin in &pins {
+        store.create(pin);
+    }
+    let result = sample_result();
+    let mut next = 0;
+    c.bench_function(&format!("respond/{}", name), |b| {
+        b.iter(|| {
+            next = (next + 1) % RESIDENT_PINS;
+            assert!(store.respond(&pins[next], &result));
+        })
+    });
+}
+
+/// Polls that find no result yet, which is what clients spend most of their time doing.
+fn bench_poll<S: BenchStore>(c: &mut Criterion, name: &str, new_store: impl Fn() -> S) {
+    let store = new_store();
+    let pins: Vec<String> = (0..RESIDENT_PINS).map(pin_for).collect();
+    for pin in &pins {
+        store.create(pin);
+    }
+    let mut next = 0;
+    c.bench_function(&format!("poll/{}", name), |b| {
+        b.iter(|| {
+            next = (next + 1) % RESIDENT_PINS;
+            assert!(store.poll(&pins[next]).is_some());
+        })
+    });
+}
+
+/// Runs `iters` create/respond/poll lifecycles split across `threads`,
+/// returning the wall-clock time taken.
+fn run_lifecycles<S: BenchStore>(store: &S, threads: usize, iters: u64) -> Duration {
+    let per_thread = (iters as usize).div_ceil(threads);
+    let start = Instant::now();
+    let workers: Vec<_> = (0..threads)
+        .map(|thread| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                let result = sample_result();
+                for n in 0..per_thread {
+                    let pin = pin_for(thread * per_thread + n);
+                    assert!(store.create(&pin));
+                    assert!(store.respond(&pin, &result));
+                    assert!(store.poll(&pin).unwrap().result.is_some());
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_lifecycle<S: BenchStore>(c: &mut Criterion, name: &str, new_store: impl Fn() -> S) {
+    let mut group = c.benchmark_group("lifecycle");
+    for threads in [1, THREADS] {
+        group.bench_with_input(BenchmarkId::new(name, threads), &threads, |b, &threads| {
+            b.iter_custom(|iters| run_lifecycles(&new_store(), threads, iters))
+        });
+    }
+    group.finish();
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let sharded = || Arc::new(MemoryStore::new());
+    bench_create(c, "evmap", LegacyStore::new);
+    bench_create(c, "sharded", sharded);
+    bench_respond(c, "evmap", LegacyStore::new);
+    bench_respond(c, "sharded", sharded);
+    bench_poll(c, "evmap", LegacyStore::new);
+    bench_poll(c, "sharded", sharded);
+    bench_lifecycle(c, "evmap", LegacyStore::new);
+    bench_lifecycle(c, "sharded", sharded);
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -8,8 +8,17 @@ set -e
 echo "🚀 Configgymajiggy Deployment Script"
 echo "==========================="
 
+# Snapshots are sealed with MASTER_KEYS, so one is made once and kept in .env.
+ensure_master_keys() {
+    if ! grep -qs '^MASTER_KEYS=' .env; then
+        echo "🔑 Generating MASTER_KEYS in .env (back it up separately from the data volume)"
+        echo "MASTER_KEYS=$(date +%Y-%m)=$(openssl rand -hex 32)" >> .env
+    fi
+}
+
 case "${1:-deploy}" in
     "deploy")
+        ensure_master_keys
         echo "📦 Building and deploying Configgymajiggy service..."
         docker-compose up -d --build
         echo "✅ Service deployed successfully!"
@@ -17,6 +26,7 @@ case "${1:-deploy}" in
         echo "🔍 Check health: curl http://localhost:8080/health"
         ;;
     "start")
+        ensure_master_keys
         echo "▶️  Starting Configgymajiggy service..."
         docker-compose up -d
         echo "✅ Service started!"
@@ -45,6 +55,7 @@ case "${1:-deploy}" in
     "update")
         echo "🔄 Updating service..."
         git pull
+        ensure_master_keys
         docker-compose build
         docker-compose up -d
         echo "✅ Service updated!"
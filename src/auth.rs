@@ -0,0 +1,268 @@
+//! What callers may do, and the API keys that say so. Each key is allowed
+//! some kinds of request in the namespaces matching its patterns. Keys are
+//! read from a JSON file holding only their hashes:
+//!
+//! ```json
+//! [
+//!   {
+//!     "id": "tv-app",
+//!     "secret_sha256": "<hex SHA-256 of the key>",
+//!     "scopes": ["create", "poll"],
+//!     "namespaces": ["tv", "tv-*"]
+//!   }
+//! ]
+//! ```
+
+use crate::ratelimit::Operation;
+use crate::token;
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A kind of request a caller may be allowed to make.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Create,
+    Poll,
+    Submit,
+    /// The admin endpoints, for the caller's namespaces.
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "create" => Ok(Scope::Create),
+            "poll" => Ok(Scope::Poll),
+            "submit" => Ok(Scope::Submit),
+            "admin" => Ok(Scope::Admin),
+            _ => anyhow::bail!("Unknown scope {:?}", s),
+        }
+    }
+}
+
+/// What an authenticated caller may do.
+#[derive(Debug, Deserialize)]
+pub struct Grant {
+    /// Names the caller in logs, since its credentials are never shown.
+    pub id: String,
+    scopes: Vec<Scope>,
+    /// Namespaces the caller may use, where `*` matches anything.
+    namespaces: Vec<String>,
+}
+
+impl Grant {
+    pub fn new(id: String, scopes: Vec<Scope>, namespaces: Vec<String>) -> Self {
+        Grant {
+            id,
+            scopes,
+            namespaces,
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    pub fn covers(&self, namespace: &str) -> bool {
+        self.namespaces
+            .iter()
+            .any(|pattern| matches_pattern(pattern, namespace))
+    }
+
+    /// Whether the caller may make a `scope` request in `namespace`. Admin
+    /// requests need not be for a namespace.
+    pub fn check(&self, scope: Scope, namespace: Option<&str>) -> Result<(), Denied> {
+        if !self.has_scope(scope) {
+            return Err(Denied::Scope);
+        }
+        if namespace.is_some_and(|namespace| !self.covers(namespace)) {
+            return Err(Denied::Namespace);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKey {
+    #[serde(flatten)]
+    grant: Grant,
+    secret_sha256: String,
+}
+
+impl From<Operation> for Scope {
+    fn from(operation: Operation) -> Self {
+        match operation {
+            Operation::Create => Scope::Create,
+            Operation::Poll => Scope::Poll,
+            Operation::Submit => Scope::Submit,
+        }
+    }
+}
+
+/// Why a request was not let through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// No credentials were presented.
+    Missing,
+    /// The API key presented is not one of ours.
+    Unknown,
+    /// The bearer token presented is malformed, or not signed by a trusted key
+    /// for this service.
+    Invalid,
+    /// The bearer token presented has expired.
+    Expired,
+    /// The caller may not make this kind of request.
+    Scope,
+    /// The caller may not use this namespace.
+    Namespace,
+}
+
+/// The configured keys, looked up by hash.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    by_hash: HashMap<String, Arc<Grant>>,
+}
+
+impl ApiKeys {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read API keys from {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("Invalid API keys in {}", path.display()))
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        let keys: Vec<ApiKey> = serde_json::from_str(json)?;
+        let mut by_hash = HashMap::new();
+        for mut key in keys {
+            key.secret_sha256.make_ascii_lowercase();
+            if key.secret_sha256.len() != 64 || hex::decode(&key.secret_sha256).is_err() {
+                anyhow::bail!(
+                    "Key {:?} needs secret_sha256 as 64 hex digits",
+                    key.grant.id
+                );
+            }
+            let id = key.grant.id.clone();
+            if by_hash
+                .insert(key.secret_sha256, Arc::new(key.grant))
+                .is_some()
+            {
+                anyhow::bail!("Key {:?} has the same secret as another key", id);
+            }
+        }
+        Ok(ApiKeys { by_hash })
+    }
+
+    /// What the holder of the `presented` key may do.
+    pub fn authenticate(&self, presented: Option<&str>) -> Result<Arc<Grant>, Denied> {
+        let presented = presented.ok_or(Denied::Missing)?;
+        self.by_hash
+            .get(&token::hash(presented))
+            .cloned()
+            .ok_or(Denied::Unknown)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+}
+
+/// Glob matching where `*` stands for any run of characters.
+pub fn matches_pattern(pattern: &str, namespace: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = namespace.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` at all.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> ApiKeys {
+        ApiKeys::from_json(&format!(
+            r#"[
+                {{"id": "tv", "secret_sha256": "{}", "scopes": ["create", "poll"], "namespaces": ["tv", "tv-*"]}},
+                {{"id": "ops", "secret_sha256": "{}", "scopes": ["admin"], "namespaces": ["*"]}}
+            ]"#,
+            token::hash("tv-secret"),
+            token::hash("ops-secret").to_uppercase(),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_keys_are_limited_to_scopes_and_namespaces() {
+        let keys = keys();
+        assert_eq!(keys.len(), 2);
+        let grant = keys.authenticate(Some("tv-secret")).unwrap();
+        assert_eq!(grant.id, "tv");
+        assert_eq!(grant.check(Scope::Poll, Some("tv-lounge")), Ok(()));
+        assert_eq!(grant.check(Scope::Submit, Some("tv")), Err(Denied::Scope));
+        assert_eq!(
+            grant.check(Scope::Create, Some("tvx")),
+            Err(Denied::Namespace)
+        );
+        assert_eq!(keys.authenticate(None).unwrap_err(), Denied::Missing);
+        assert_eq!(
+            keys.authenticate(Some("guess")).unwrap_err(),
+            Denied::Unknown
+        );
+        let grant = keys.authenticate(Some("ops-secret")).unwrap();
+        assert_eq!(grant.check(Scope::Admin, None), Ok(()));
+    }
+
+    #[test]
+    fn test_namespace_patterns() {
+        assert!(matches_pattern("tv", "tv"));
+        assert!(!matches_pattern("tv", "tv2"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("tv-*", "tv-"));
+        assert!(matches_pattern("*-staging", "web-staging"));
+        assert!(matches_pattern("a*b*c", "aXbYbZc"));
+        assert!(!matches_pattern("a*b*c", "aXcYb"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn test_key_file_validation() {
+        assert!(ApiKeys::from_json("[]").unwrap().is_empty());
+        assert!(ApiKeys::from_json(
+            r#"[{"id": "x", "secret_sha256": "plain", "scopes": [], "namespaces": []}]"#
+        )
+        .is_err());
+        assert!(ApiKeys::from_json(
+            r#"[{"id": "x", "secret_sha256": "00", "scopes": ["write"], "namespaces": []}]"#
+        )
+        .is_err());
+        let hash = token::hash("same");
+        let twice = format!(
+            r#"[{{"id": "a", "secret_sha256": "{hash}", "scopes": [], "namespaces": []}},
+                {{"id": "b", "secret_sha256": "{hash}", "scopes": [], "namespaces": []}}]"#
+        );
+        assert!(ApiKeys::from_json(&twice).is_err());
+    }
+}
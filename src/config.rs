@@ -0,0 +1,424 @@
+use crate::jwt::{JwksSource, JwtSettings};
+use crate::lockout::LockoutPolicy;
+use crate::pow::PowPolicy;
+use crate::proxy::TrustedProxies;
+use crate::ratelimit::RateLimits;
+use crate::seal::Keyring;
+use crate::store::wal::FsyncPolicy;
+use crate::store::StoreBackend;
+use crate::tls::{NamespacePatterns, TlsSettings};
+use crate::DEFAULT_TTL_SECS;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:8080";
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u32 = 30;
+const DEFAULT_SQLITE_PATH: &str = "pins.sqlite3";
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1/";
+const DEFAULT_REDIS_KEY_PREFIX: &str = "configgymajiggy:";
+const DEFAULT_MIN_TTL_SECS: u32 = 30;
+const DEFAULT_MAX_TTL_SECS: u32 = 60 * 60;
+const DEFAULT_MAX_LIFETIME_SECS: u32 = 2 * 60 * 60;
+const DEFAULT_TOMBSTONE_SECS: u32 = 5 * 60;
+const DEFAULT_MAX_WAIT_SECS: u32 = 30;
+/// RFC 8628 suggests 5 seconds when the server does not say otherwise.
+const DEFAULT_DEVICE_POLL_INTERVAL_SECS: u32 = 5;
+const DEFAULT_JWT_NAMESPACES_CLAIM: &str = "namespaces";
+const DEFAULT_JWT_SCOPES_CLAIM: &str = "scope";
+const DEFAULT_JWKS_REFRESH_SECS: u32 = 5 * 60;
+
+/// Runtime configuration, read from the environment (and `.env` via dotenvy).
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind_address: String,
+    pub store_backend: StoreBackend,
+    /// Where the memory backend writes snapshots. Persistence is disabled when unset.
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval_secs: u32,
+    /// Journal every mutation here between snapshots. Requires `snapshot_path`.
+    pub wal_path: Option<PathBuf>,
+    pub wal_fsync: FsyncPolicy,
+    /// Database file for the sqlite backend.
+    pub sqlite_path: PathBuf,
+    /// Server for the redis backend.
+    pub redis_url: String,
+    /// Prepended to every key, so several deployments can share one Redis.
+    pub redis_key_prefix: String,
+    pub ttl: TtlPolicy,
+    /// The longest a long poll may hold its request open.
+    pub max_wait_secs: u32,
+    /// Where users enter a device flow's user code. The device flow is off when unset.
+    pub device_verification_uri: Option<String>,
+    /// How often device flow clients may poll for their token.
+    pub device_poll_interval_secs: u32,
+    /// Per-client budgets for creating, polling and submitting to pins.
+    pub rate_limits: RateLimits,
+    /// Budgets shared by every client of a namespace. Off unless configured.
+    pub namespace_rate_limits: RateLimits,
+    /// Reverse proxies whose `X-Forwarded-For` says who the client is.
+    pub trusted_proxies: TrustedProxies,
+    /// When clients guessing pins are slowed down and banned.
+    pub lockout: LockoutPolicy,
+    /// Proof of work asked of clients creating pins.
+    pub pow: PowPolicy,
+    /// Bearer token for the admin endpoints, which are off when unset.
+    pub admin_token: Option<String>,
+    /// JSON file of API keys. Anyone may use the service when unset.
+    pub api_keys_path: Option<PathBuf>,
+    /// Bearer JWTs accepted alongside any API keys. Off when unset.
+    pub jwt: Option<JwtSettings>,
+    /// How often the JWKS is loaded again, to pick up rotated keys.
+    pub jwks_refresh_secs: u32,
+    /// Serve HTTPS with this certificate and key. Plain HTTP when unset.
+    pub tls: Option<TlsSettings>,
+    /// Keys results are sealed with at rest. A random key is made up at
+    /// startup when unset, which is only allowed while nothing outlives the
+    /// process.
+    pub master_keys: Option<Keyring>,
+}
+
+/// Bounds on how long a pin may ask to live.
+#[derive(Clone, Debug)]
+pub struct TtlPolicy {
+    pub min_secs: u32,
+    /// Used when the creator does not ask for a lifetime.
+    pub default_secs: u32,
+    pub max_secs: u32,
+    /// Tighter caps for particular namespaces.
+    pub namespace_max_secs: NamespaceLimits,
+    /// How long after creation renewals stop extending a pin.
+    pub max_lifetime_secs: u32,
+    /// How long an ended pin is remembered, so late requests can be told how it ended.
+    pub tombstone_secs: u32,
+}
+
+impl Default for TtlPolicy {
+    fn default() -> Self {
+        TtlPolicy {
+            min_secs: DEFAULT_MIN_TTL_SECS,
+            default_secs: DEFAULT_TTL_SECS,
+            max_secs: DEFAULT_MAX_TTL_SECS,
+            namespace_max_secs: NamespaceLimits::default(),
+            max_lifetime_secs: DEFAULT_MAX_LIFETIME_SECS,
+            tombstone_secs: DEFAULT_TOMBSTONE_SECS,
+        }
+    }
+}
+
+impl TtlPolicy {
+    /// The lifetime a new pin in `namespace` gets when it asks for `requested` seconds.
+    pub fn resolve(&self, namespace: &str, requested: Option<u32>) -> u32 {
+        let max = match self.namespace_max_secs.0.get(namespace) {
+            Some(&limit) => limit.min(self.max_secs),
+            None => self.max_secs,
+        };
+        requested
+            .unwrap_or(self.default_secs)
+            .clamp(self.min_secs.min(max), max)
+    }
+}
+
+/// Per-namespace limits, written as `namespace=secs,namespace=secs`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceLimits(pub HashMap<String, u32>);
+
+impl FromStr for NamespaceLimits {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = HashMap::new();
+        for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let (namespace, limit) = pair
+                .rsplit_once('=')
+                .with_context(|| format!("Expected namespace=secs, got {:?}", pair))?;
+            let limit = limit
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid limit for namespace {:?}", namespace))?;
+            limits.insert(namespace.trim().to_string(), limit);
+        }
+        Ok(NamespaceLimits(limits))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: DEFAULT_BIND_ADDRESS.to_string(),
+            store_backend: StoreBackend::Memory,
+            snapshot_path: None,
+            snapshot_interval_secs: DEFAULT_SNAPSHOT_INTERVAL_SECS,
+            wal_path: None,
+            wal_fsync: FsyncPolicy::Always,
+            sqlite_path: PathBuf::from(DEFAULT_SQLITE_PATH),
+            redis_url: DEFAULT_REDIS_URL.to_string(),
+            redis_key_prefix: DEFAULT_REDIS_KEY_PREFIX.to_string(),
+            ttl: TtlPolicy::default(),
+            max_wait_secs: DEFAULT_MAX_WAIT_SECS,
+            device_verification_uri: None,
+            device_poll_interval_secs: DEFAULT_DEVICE_POLL_INTERVAL_SECS,
+            rate_limits: RateLimits::default(),
+            namespace_rate_limits: RateLimits::OFF,
+            trusted_proxies: TrustedProxies::default(),
+            lockout: LockoutPolicy::default(),
+            pow: PowPolicy::default(),
+            admin_token: None,
+            api_keys_path: None,
+            jwt: None,
+            jwks_refresh_secs: DEFAULT_JWKS_REFRESH_SECS,
+            tls: None,
+            master_keys: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let defaults = Config::default();
+        let config = Config {
+            bind_address: env_or("BIND_ADDRESS", defaults.bind_address)?,
+            store_backend: env_or("STORE_BACKEND", defaults.store_backend)?,
+            snapshot_path: env_opt("SNAPSHOT_PATH")?,
+            snapshot_interval_secs: env_or(
+                "SNAPSHOT_INTERVAL_SECS",
+                defaults.snapshot_interval_secs,
+            )?,
+            wal_path: env_opt("WAL_PATH")?,
+            wal_fsync: env_or("WAL_FSYNC", defaults.wal_fsync)?,
+            sqlite_path: env_or("SQLITE_PATH", defaults.sqlite_path)?,
+            redis_url: env_or("REDIS_URL", defaults.redis_url)?,
+            redis_key_prefix: env_or("REDIS_KEY_PREFIX", defaults.redis_key_prefix)?,
+            ttl: TtlPolicy {
+                min_secs: env_or("MIN_TTL_SECS", defaults.ttl.min_secs)?,
+                default_secs: env_or("DEFAULT_TTL_SECS", defaults.ttl.default_secs)?,
+                max_secs: env_or("MAX_TTL_SECS", defaults.ttl.max_secs)?,
+                namespace_max_secs: env_or(
+                    "NAMESPACE_MAX_TTL_SECS",
+                    defaults.ttl.namespace_max_secs,
+                )?,
+                max_lifetime_secs: env_or("MAX_LIFETIME_SECS", defaults.ttl.max_lifetime_secs)?,
+                tombstone_secs: env_or("TOMBSTONE_SECS", defaults.ttl.tombstone_secs)?,
+            },
+            max_wait_secs: env_or("MAX_WAIT_SECS", defaults.max_wait_secs)?,
+            device_verification_uri: env_opt("DEVICE_VERIFICATION_URI")?,
+            device_poll_interval_secs: env_or(
+                "DEVICE_POLL_INTERVAL_SECS",
+                defaults.device_poll_interval_secs,
+            )?,
+            rate_limits: RateLimits {
+                create: env_or("RATE_LIMIT_CREATE", defaults.rate_limits.create)?,
+                poll: env_or("RATE_LIMIT_POLL", defaults.rate_limits.poll)?,
+                submit: env_or("RATE_LIMIT_SUBMIT", defaults.rate_limits.submit)?,
+            },
+            namespace_rate_limits: RateLimits {
+                create: env_or(
+                    "RATE_LIMIT_NAMESPACE_CREATE",
+                    defaults.namespace_rate_limits.create,
+                )?,
+                poll: env_or(
+                    "RATE_LIMIT_NAMESPACE_POLL",
+                    defaults.namespace_rate_limits.poll,
+                )?,
+                submit: env_or(
+                    "RATE_LIMIT_NAMESPACE_SUBMIT",
+                    defaults.namespace_rate_limits.submit,
+                )?,
+            },
+            trusted_proxies: env_or("TRUSTED_PROXIES", defaults.trusted_proxies)?,
+            lockout: LockoutPolicy {
+                delay_after: env_or("LOCKOUT_DELAY_AFTER", defaults.lockout.delay_after)?,
+                ban_after: env_or("LOCKOUT_BAN_AFTER", defaults.lockout.ban_after)?,
+                window_secs: env_or("LOCKOUT_WINDOW_SECS", defaults.lockout.window_secs)?,
+                ban_secs: env_or("LOCKOUT_BAN_SECS", defaults.lockout.ban_secs)?,
+            },
+            pow: PowPolicy {
+                min_difficulty: env_or("POW_DIFFICULTY", defaults.pow.min_difficulty)?,
+                max_difficulty: env_or("POW_MAX_DIFFICULTY", defaults.pow.max_difficulty)?,
+                challenge_secs: env_or("POW_CHALLENGE_SECS", defaults.pow.challenge_secs)?,
+            },
+            admin_token: env_opt("ADMIN_TOKEN")?,
+            api_keys_path: env_opt("API_KEYS_PATH")?,
+            jwt: jwt_from_env()?,
+            jwks_refresh_secs: env_or("JWT_JWKS_REFRESH_SECS", defaults.jwks_refresh_secs)?,
+            tls: tls_from_env()?,
+            master_keys: master_keys_from_env()?,
+        };
+        let ttl = &config.ttl;
+        if ttl.min_secs == 0 || ttl.min_secs > ttl.default_secs || ttl.default_secs > ttl.max_secs {
+            anyhow::bail!(
+                "TTL limits must satisfy 0 < MIN_TTL_SECS <= DEFAULT_TTL_SECS <= MAX_TTL_SECS"
+            );
+        }
+        if ttl.max_lifetime_secs < ttl.max_secs {
+            anyhow::bail!("MAX_LIFETIME_SECS must be at least MAX_TTL_SECS");
+        }
+        if config.device_poll_interval_secs == 0 {
+            anyhow::bail!("DEVICE_POLL_INTERVAL_SECS must be at least 1");
+        }
+        let lockout = &config.lockout;
+        if lockout.delay_after >= lockout.ban_after {
+            anyhow::bail!("LOCKOUT_DELAY_AFTER must be less than LOCKOUT_BAN_AFTER");
+        }
+        if lockout.window_secs == 0 || lockout.ban_secs == 0 {
+            anyhow::bail!("LOCKOUT_WINDOW_SECS and LOCKOUT_BAN_SECS must be at least 1");
+        }
+        let pow = &config.pow;
+        if pow.min_difficulty > 0
+            && (pow.max_difficulty < pow.min_difficulty || pow.max_difficulty > 32)
+        {
+            anyhow::bail!("POW_MAX_DIFFICULTY must be between POW_DIFFICULTY and 32");
+        }
+        if pow.challenge_secs == 0 {
+            anyhow::bail!("POW_CHALLENGE_SECS must be at least 1");
+        }
+        if config.jwks_refresh_secs == 0 {
+            anyhow::bail!("JWT_JWKS_REFRESH_SECS must be at least 1");
+        }
+        if config.snapshot_interval_secs == 0 {
+            anyhow::bail!("SNAPSHOT_INTERVAL_SECS must be at least 1");
+        }
+        if config.wal_path.is_some() && config.snapshot_path.is_none() {
+            anyhow::bail!("WAL_PATH requires SNAPSHOT_PATH so the log can be compacted");
+        }
+        let persisted =
+            config.snapshot_path.is_some() || config.store_backend != StoreBackend::Memory;
+        if persisted && config.master_keys.is_none() {
+            anyhow::bail!(
+                "MASTER_KEYS is required with SNAPSHOT_PATH or a shared backend, or stored results could not be read after a restart or by other replicas"
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// JWT settings, when a JWKS is configured. A token's issuer and audience are
+/// always checked, so both must be given too.
+fn jwt_from_env() -> anyhow::Result<Option<JwtSettings>> {
+    let jwks = match (env_opt("JWT_JWKS_PATH")?, env_opt("JWT_JWKS_URL")?) {
+        (None, None) => return Ok(None),
+        (Some(path), None) => JwksSource::File(path),
+        (None, Some(url)) => JwksSource::Url(url),
+        (Some(_), Some(_)) => anyhow::bail!("Set only one of JWT_JWKS_PATH and JWT_JWKS_URL"),
+    };
+    let (Some(issuer), Some(audience)) = (env_opt("JWT_ISSUER")?, env_opt("JWT_AUDIENCE")?) else {
+        anyhow::bail!("JWT_ISSUER and JWT_AUDIENCE are required with a JWKS");
+    };
+    Ok(Some(JwtSettings {
+        jwks,
+        issuer,
+        audience,
+        namespaces_claim: env_or(
+            "JWT_NAMESPACES_CLAIM",
+            DEFAULT_JWT_NAMESPACES_CLAIM.to_string(),
+        )?,
+        scopes_claim: env_or("JWT_SCOPES_CLAIM", DEFAULT_JWT_SCOPES_CLAIM.to_string())?,
+    }))
+}
+
+/// TLS settings, when a certificate is configured. Client certificates can
+/// only be required once there is a CA to check them against.
+fn tls_from_env() -> anyhow::Result<Option<TlsSettings>> {
+    let client_ca_path = env_opt("TLS_CLIENT_CA_PATH")?;
+    let client_cert_namespaces: NamespacePatterns =
+        env_or("TLS_CLIENT_CERT_NAMESPACES", Default::default())?;
+    let (cert_path, key_path) = match (env_opt("TLS_CERT_PATH")?, env_opt("TLS_KEY_PATH")?) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) if client_ca_path.is_none() && client_cert_namespaces.is_empty() => {
+            return Ok(None)
+        }
+        (None, None) => anyhow::bail!("Client certificates require TLS_CERT_PATH and TLS_KEY_PATH"),
+        _ => anyhow::bail!("Set both TLS_CERT_PATH and TLS_KEY_PATH, or neither"),
+    };
+    let settings = TlsSettings {
+        cert_path,
+        key_path,
+        client_ca_path,
+        client_cert_namespaces,
+    };
+    if settings.client_ca_path.is_none() && !settings.client_cert_namespaces.is_empty() {
+        anyhow::bail!("TLS_CLIENT_CERT_NAMESPACES requires TLS_CLIENT_CA_PATH");
+    }
+    Ok(Some(settings))
+}
+
+/// The master keys, read without ever quoting them back in an error.
+fn master_keys_from_env() -> anyhow::Result<Option<Keyring>> {
+    match std::env::var("MASTER_KEYS") {
+        Ok(value) if !value.trim().is_empty() => value
+            .parse()
+            .map(Some)
+            .context("Invalid value for MASTER_KEYS"),
+        _ => Ok(None),
+    }
+}
+
+fn env_opt<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(Into::into)
+            .with_context(|| format!("Invalid value for {}: {:?}", name, value)),
+        _ => Ok(None),
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    Ok(env_opt(name)?.unwrap_or(default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(namespace_limits: &str) -> TtlPolicy {
+        TtlPolicy {
+            min_secs: 30,
+            default_secs: 600,
+            max_secs: 3600,
+            namespace_max_secs: namespace_limits.parse().unwrap(),
+            max_lifetime_secs: 7200,
+            tombstone_secs: 300,
+        }
+    }
+
+    #[test]
+    fn test_ttl_is_clamped_to_server_limits() {
+        let policy = policy("");
+        assert_eq!(policy.resolve("ns", None), 600);
+        assert_eq!(policy.resolve("ns", Some(120)), 120);
+        assert_eq!(policy.resolve("ns", Some(1)), 30);
+        assert_eq!(policy.resolve("ns", Some(86_400)), 3600);
+    }
+
+    #[test]
+    fn test_ttl_is_clamped_to_namespace_limits() {
+        let policy = policy("tv=120, tiny=10, huge=99999");
+        assert_eq!(policy.resolve("tv", None), 120);
+        assert_eq!(policy.resolve("tv", Some(60)), 60);
+        assert_eq!(policy.resolve("tv", Some(3600)), 120);
+        // A namespace cap below the server minimum wins.
+        assert_eq!(policy.resolve("tiny", Some(60)), 10);
+        // A namespace cannot raise the server maximum.
+        assert_eq!(policy.resolve("huge", Some(7200)), 3600);
+    }
+
+    #[test]
+    fn test_namespace_limits_parsing() {
+        assert_eq!(
+            "".parse::<NamespaceLimits>().unwrap(),
+            NamespaceLimits::default()
+        );
+        assert!("tv".parse::<NamespaceLimits>().is_err());
+        assert!("tv=soon".parse::<NamespaceLimits>().is_err());
+    }
+}
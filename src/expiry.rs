@@ -0,0 +1,305 @@
+//! Expires each pin at its own deadline, rather than sweeping the whole store
+//! on a timer, and tells anyone watching the pin when it does.
+
+use crate::clock::Clock;
+use crate::store::{create_key, PinItem, PinStore};
+use crate::waiters::Waiters;
+use chrono::prelude::{DateTime, Utc};
+use log::{error, info};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Deadline {
+    at: DateTime<Utc>,
+    namespace: String,
+    pin: String,
+}
+
+/// A min-heap of pin deadlines drained by [`Expiry::run`].
+///
+/// Entries are never removed early: when a pin is answered, taken or
+/// rescheduled, its old entry stays queued and the store ignores it once it
+/// comes due, because the pin is gone or its deadline has moved.
+pub struct Expiry {
+    store: Arc<dyn PinStore>,
+    clock: Arc<dyn Clock>,
+    waiters: Arc<Waiters>,
+    deadlines: Mutex<BinaryHeap<Reverse<Deadline>>>,
+    changed: Notify,
+}
+
+impl Expiry {
+    pub fn new(store: Arc<dyn PinStore>, clock: Arc<dyn Clock>, waiters: Arc<Waiters>) -> Self {
+        Expiry {
+            store,
+            clock,
+            waiters,
+            deadlines: Mutex::new(BinaryHeap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BinaryHeap<Reverse<Deadline>>> {
+        // The heap is always left consistent, so a panic elsewhere does not invalidate it.
+        self.deadlines
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues `item` to be dropped once it and its tombstone go stale. A pin
+    /// that will leave a tombstone is also visited when it expires, so that
+    /// anyone watching it hears about it.
+    pub fn schedule(&self, namespace: &str, item: &PinItem) {
+        if self.store.expires_natively() {
+            return;
+        }
+        let purge_at = item.purge_at();
+        let mut due = vec![purge_at];
+        let ended = item.revoked_at.is_some() || item.consumed_at.is_some();
+        if !ended && item.expires_at < purge_at {
+            due.push(item.expires_at);
+        }
+
+        let mut deadlines = self.lock();
+        let is_earliest = deadlines
+            .peek()
+            .is_none_or(|Reverse(next)| due.iter().any(|at| *at < next.at));
+        for at in due {
+            deadlines.push(Reverse(Deadline {
+                at,
+                namespace: namespace.to_string(),
+                pin: item.pin.clone(),
+            }));
+        }
+        drop(deadlines);
+        if is_earliest {
+            self.changed.notify_one();
+        }
+    }
+
+    /// Queues every pin already in the store, e.g. after restoring it from disk.
+    pub fn schedule_existing(&self) -> anyhow::Result<()> {
+        if self.store.expires_natively() {
+            return Ok(());
+        }
+        let existing = self.store.deadlines()?;
+        let mut deadlines = self.lock();
+        for (namespace, pin, at) in existing {
+            deadlines.push(Reverse(Deadline { at, namespace, pin }));
+        }
+        drop(deadlines);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Number of queued deadlines, including ones that will turn out to be moot.
+    pub fn pending(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn pop_due(&self, now: DateTime<Utc>) -> Vec<Deadline> {
+        let mut deadlines = self.lock();
+        let mut due = Vec::new();
+        while deadlines.peek().is_some_and(|Reverse(next)| next.at <= now) {
+            if let Some(Reverse(deadline)) = deadlines.pop() {
+                due.push(deadline);
+            }
+        }
+        due
+    }
+
+    fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.lock().peek().map(|Reverse(next)| next.at)
+    }
+
+    /// Expires pins as their deadlines pass. Runs until the runtime shuts down.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let now = self.clock.now();
+            let due = self.pop_due(now);
+            if !due.is_empty() {
+                let store = self.store.clone();
+                let waiters = self.waiters.clone();
+                let expired = tokio::task::spawn_blocking(move || {
+                    for deadline in due {
+                        match store.expire_if_due(&deadline.namespace, &deadline.pin, now) {
+                            Ok(true) => info!(
+                                "Expired pin {}",
+                                create_key(&deadline.namespace, &deadline.pin)
+                            ),
+                            // Its tombstone stays, but not the result nobody collected.
+                            Ok(false) => {
+                                let dropped =
+                                    store.update(&deadline.namespace, &deadline.pin, &mut |item| {
+                                        item.drop_expired_result(now)
+                                    });
+                                if let Err(e) = dropped {
+                                    error!("Failed to drop an expired result: {:#}", e);
+                                }
+                            }
+                            Err(e) => error!("Failed to expire a pin: {:#}", e),
+                        }
+                        // Watchers look for themselves whether the pin actually ended.
+                        waiters.notify(&deadline.namespace, &deadline.pin);
+                    }
+                })
+                .await;
+                if let Err(e) = expired {
+                    error!("Expiry batch panicked: {}", e);
+                }
+            }
+
+            let changed = self.changed.notified();
+            match self.next_deadline() {
+                Some(at) => {
+                    let wait = (at - self.clock.now()).to_std().unwrap_or_default();
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = changed => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::store::MemoryStore;
+    use chrono::Duration;
+
+    struct Harness {
+        store: Arc<dyn PinStore>,
+        clock: Arc<ManualClock>,
+        expiry: Arc<Expiry>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let store: Arc<dyn PinStore> = Arc::new(MemoryStore::new());
+            let clock = Arc::new(ManualClock::new(Utc::now()));
+            let expiry = Arc::new(Expiry::new(
+                store.clone(),
+                clock.clone(),
+                Arc::new(Waiters::new()),
+            ));
+            Harness {
+                store,
+                clock,
+                expiry,
+            }
+        }
+
+        fn start(&self) {
+            tokio::spawn(self.expiry.clone().run());
+        }
+
+        fn item(&self, pin: &str, ttl_secs: u32) -> PinItem {
+            PinItem::new(pin.to_string(), None, self.clock.now()).with_ttl(ttl_secs)
+        }
+
+        /// Creates a pin that lives for `ttl_secs` from now.
+        fn create(&self, pin: &str, ttl_secs: u32) {
+            let item = self.item(pin, ttl_secs);
+            self.expiry.schedule("ns", &item);
+            self.store.create_if_absent("ns", item).unwrap();
+        }
+
+        fn exists(&self, pin: &str) -> bool {
+            self.store.get("ns", pin).unwrap().is_some()
+        }
+
+        /// Moves the manual clock and tokio's paused clock forward together.
+        async fn advance(&self, secs: i64) {
+            self.clock.advance(Duration::seconds(secs));
+            tokio::time::sleep(std::time::Duration::from_secs(secs as u64)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pin_expires_at_its_deadline() {
+        let harness = Harness::new();
+        harness.start();
+        harness.create("SOON", 120);
+        harness.create("LATE", 480);
+
+        harness.advance(60).await;
+        assert!(harness.exists("SOON"));
+
+        harness.advance(120).await;
+        assert!(!harness.exists("SOON"));
+        assert!(harness.exists("LATE"));
+        assert_eq!(harness.expiry.pending(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pin_lives_for_its_ttl() {
+        let harness = Harness::new();
+        harness.start();
+        harness.create("ABCD", 600);
+
+        harness.advance(599).await;
+        assert!(harness.exists("ABCD"));
+
+        harness.advance(2).await;
+        assert!(!harness.exists("ABCD"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_earlier_deadline_wakes_the_task() {
+        let harness = Harness::new();
+        harness.start();
+        harness.create("LATE", 480);
+        // Let the task go to sleep on the late deadline first.
+        tokio::task::yield_now().await;
+
+        harness.create("SOON", 60);
+        harness.advance(120).await;
+        assert!(!harness.exists("SOON"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_moved_deadline_outlives_old_entry() {
+        let harness = Harness::new();
+        harness.start();
+        harness.create("ABCD", 60);
+
+        harness.advance(30).await;
+        let now = harness.clock.now();
+        let answered = harness
+            .store
+            .update("ns", "ABCD", &mut |item| {
+                item.touch(now, 3600);
+                true
+            })
+            .unwrap()
+            .unwrap();
+        harness.expiry.schedule("ns", &answered);
+
+        harness.advance(40).await;
+        assert!(harness.exists("ABCD"));
+
+        harness.advance(30).await;
+        assert!(!harness.exists("ABCD"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_schedule_existing_pins() {
+        let harness = Harness::new();
+        harness
+            .store
+            .create_if_absent("ns", harness.item("SOON", 60))
+            .unwrap();
+        harness.expiry.schedule_existing().unwrap();
+        harness.start();
+
+        harness.advance(120).await;
+        assert!(!harness.exists("SOON"));
+    }
+}
@@ -0,0 +1,314 @@
+//! Bearer JWTs from an identity provider, checked against the provider's
+//! JWKS and turned into a [`Grant`] from their claims.
+
+use crate::auth::{Denied, Grant, Scope};
+use anyhow::Context;
+use chrono::prelude::{DateTime, Utc};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Allowance for clocks that disagree with the identity provider's.
+const LEEWAY_SECS: i64 = 60;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the keys tokens are signed with come from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JwksSource {
+    File(PathBuf),
+    /// Fetched at startup and again on every refresh, keeping the last good
+    /// set if a fetch fails.
+    Url(String),
+}
+
+/// What a token must say to be accepted, and where it says what its bearer may do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JwtSettings {
+    pub jwks: JwksSource,
+    pub issuer: String,
+    pub audience: String,
+    /// Claim listing the namespace patterns the bearer may use.
+    pub namespaces_claim: String,
+    /// Claim listing the bearer's scopes. Scopes this service does not know,
+    /// such as `openid`, are ignored.
+    pub scopes_claim: String,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    exp: i64,
+    nbf: Option<i64>,
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+/// Checks bearer tokens against the configured keys.
+pub struct JwtVerifier {
+    settings: JwtSettings,
+    keys: RwLock<JwkSet>,
+}
+
+impl JwtVerifier {
+    /// Loads the keys, failing if there are none to be had.
+    pub fn new(settings: JwtSettings) -> anyhow::Result<Self> {
+        let keys = fetch(&settings.jwks)?;
+        Ok(JwtVerifier {
+            settings,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Loads the keys again, so the provider can rotate them.
+    pub fn refresh(&self) -> anyhow::Result<()> {
+        let keys = fetch(&self.settings.jwks)?;
+        *self
+            .keys
+            .write()
+            .map_err(|_| anyhow::anyhow!("JWKS lock poisoned"))? = keys;
+        Ok(())
+    }
+
+    /// Number of keys tokens may be signed with.
+    pub fn key_count(&self) -> usize {
+        self.keys.read().map_or(0, |keys| keys.keys.len())
+    }
+
+    /// What the bearer of `token` may do, if it is signed by one of the keys,
+    /// meant for us and still valid at `now`.
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<Grant, Denied> {
+        let header = jsonwebtoken::decode_header(token).map_err(|_| Denied::Invalid)?;
+        let key = {
+            let keys = self.keys.read().map_err(|_| Denied::Invalid)?;
+            let jwk = match &header.kid {
+                Some(kid) => keys.find(kid),
+                // Without a key id, only an unambiguous set will do.
+                None if keys.keys.len() == 1 => keys.keys.first(),
+                None => None,
+            };
+            DecodingKey::from_jwk(jwk.ok_or(Denied::Invalid)?).map_err(|_| Denied::Invalid)?
+        };
+
+        // Checking the token's times against our own clock, not the library's.
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.settings.issuer]);
+        validation.set_audience(&[&self.settings.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.validate_exp = false;
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|_| Denied::Invalid)?
+            .claims;
+        let now = now.timestamp();
+        if claims.exp + LEEWAY_SECS <= now {
+            return Err(Denied::Expired);
+        }
+        if claims.nbf.is_some_and(|nbf| nbf - LEEWAY_SECS > now) {
+            return Err(Denied::Invalid);
+        }
+
+        let scopes = words(claims.other.get(&self.settings.scopes_claim))
+            .filter_map(|scope| scope.parse::<Scope>().ok())
+            .collect();
+        let namespaces = words(claims.other.get(&self.settings.namespaces_claim))
+            .map(str::to_string)
+            .collect();
+        let subject = claims.sub.as_deref().unwrap_or("unknown subject");
+        Ok(Grant::new(format!("jwt:{}", subject), scopes, namespaces))
+    }
+}
+
+/// Whether a bearer credential is a JWT rather than an API key.
+pub fn looks_like_jwt(credential: &str) -> bool {
+    credential.split('.').count() == 3
+}
+
+/// A claim holding either a list of strings or one space-separated string,
+/// the way OAuth writes `scope`.
+fn words(claim: Option<&Value>) -> Box<dyn Iterator<Item = &str> + '_> {
+    match claim {
+        Some(Value::String(words)) => Box::new(words.split_whitespace()),
+        Some(Value::Array(items)) => Box::new(items.iter().filter_map(Value::as_str)),
+        _ => Box::new(std::iter::empty()),
+    }
+}
+
+fn fetch(source: &JwksSource) -> anyhow::Result<JwkSet> {
+    let (json, origin) = match source {
+        JwksSource::File(path) => (
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read JWKS from {}", path.display()))?,
+            path.display().to_string(),
+        ),
+        JwksSource::Url(url) => {
+            let agent: ureq::Agent = ureq::Agent::config_builder()
+                .timeout_global(Some(FETCH_TIMEOUT))
+                .build()
+                .into();
+            let json = agent
+                .get(url)
+                .call()
+                .and_then(|mut response| response.body_mut().read_to_string())
+                .with_context(|| format!("Could not fetch JWKS from {}", url))?;
+            (json, url.clone())
+        }
+    };
+    let keys: JwkSet =
+        serde_json::from_str(&json).with_context(|| format!("Invalid JWKS from {}", origin))?;
+    if keys.keys.is_empty() {
+        anyhow::bail!("No keys in the JWKS from {}", origin);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// A freshly generated signing key, and its JWK.
+    fn signing_key(kid: &str) -> (EncodingKey, Value) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let jwk = json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "kid": kid,
+            "alg": "EdDSA",
+            "x": URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+        });
+        (EncodingKey::from_ed_der(pkcs8.as_ref()), jwk)
+    }
+
+    fn sign(key: &EncodingKey, kid: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(kid.to_string());
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+
+    fn settings(jwks: JwksSource) -> JwtSettings {
+        JwtSettings {
+            jwks,
+            issuer: "https://id.example.com/".to_string(),
+            audience: "configgymajiggy".to_string(),
+            namespaces_claim: "namespaces".to_string(),
+            scopes_claim: "scope".to_string(),
+        }
+    }
+
+    fn claims(now: DateTime<Utc>) -> Value {
+        json!({
+            "iss": "https://id.example.com/",
+            "aud": "configgymajiggy",
+            "sub": "alice",
+            "exp": now.timestamp() + 300,
+            "scope": "openid poll submit",
+            "namespaces": ["tv-*"],
+        })
+    }
+
+    #[test]
+    fn test_tokens_map_claims_to_grants() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key, jwk) = signing_key("one");
+        let path = dir.path().join("jwks.json");
+        std::fs::write(&path, json!({ "keys": [jwk] }).to_string()).unwrap();
+        let verifier = JwtVerifier::new(settings(JwksSource::File(path))).unwrap();
+        let now = Utc::now();
+
+        let token = sign(&key, "one", claims(now));
+        assert!(looks_like_jwt(&token));
+        let grant = verifier.verify(&token, now).unwrap();
+        assert_eq!(grant.id, "jwt:alice");
+        assert_eq!(grant.check(Scope::Poll, Some("tv-lounge")), Ok(()));
+        assert_eq!(grant.check(Scope::Create, Some("tv")), Err(Denied::Scope));
+        assert_eq!(
+            grant.check(Scope::Submit, Some("kitchen")),
+            Err(Denied::Namespace)
+        );
+
+        let later = now + chrono::Duration::seconds(300 + LEEWAY_SECS);
+        assert_eq!(verifier.verify(&token, later).unwrap_err(), Denied::Expired);
+    }
+
+    #[test]
+    fn test_tokens_must_be_ours() {
+        let dir = tempfile::tempdir().unwrap();
+        let (key, jwk) = signing_key("one");
+        let (stranger, _) = signing_key("one");
+        let path = dir.path().join("jwks.json");
+        std::fs::write(&path, json!({ "keys": [jwk] }).to_string()).unwrap();
+        let verifier = JwtVerifier::new(settings(JwksSource::File(path))).unwrap();
+        let now = Utc::now();
+
+        let mut wrong_issuer = claims(now);
+        wrong_issuer["iss"] = json!("https://evil.example.com/");
+        let mut wrong_audience = claims(now);
+        wrong_audience["aud"] = json!("someone-else");
+        let mut no_expiry = claims(now);
+        no_expiry.as_object_mut().unwrap().remove("exp");
+        for token in [
+            sign(&stranger, "one", claims(now)),
+            sign(&key, "two", claims(now)),
+            sign(&key, "one", wrong_issuer),
+            sign(&key, "one", wrong_audience),
+            sign(&key, "one", no_expiry),
+            "not.a.jwt".to_string(),
+        ] {
+            assert_eq!(verifier.verify(&token, now).unwrap_err(), Denied::Invalid);
+        }
+    }
+
+    #[test]
+    fn test_keys_are_fetched_and_refreshed_from_url() {
+        let (old_key, old_jwk) = signing_key("old");
+        let (new_key, new_jwk) = signing_key("new");
+        let served = vec![json!({ "keys": [old_jwk] }), json!({ "keys": [new_jwk] })];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/jwks.json", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (stream, jwks) in listener.incoming().zip(served) {
+                let mut stream = stream.unwrap();
+                // Reading the request up to the blank line that ends its headers.
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let body = jwks.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        let verifier = JwtVerifier::new(settings(JwksSource::Url(url))).unwrap();
+        let now = Utc::now();
+        let old_token = sign(&old_key, "old", claims(now));
+        let new_token = sign(&new_key, "new", claims(now));
+        assert!(verifier.verify(&old_token, now).is_ok());
+        assert!(verifier.verify(&new_token, now).is_err());
+
+        verifier.refresh().unwrap();
+        assert!(verifier.verify(&new_token, now).is_ok());
+        assert!(verifier.verify(&old_token, now).is_err());
+        // The server is gone, so the last good keys stay.
+        assert!(verifier.refresh().is_err());
+        assert_eq!(verifier.key_count(), 1);
+        assert!(verifier.verify(&new_token, now).is_ok());
+    }
+}
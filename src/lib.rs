@@ -0,0 +1,22 @@
+//! Everything configgymajiggy does besides routing HTTP requests: pin storage
+//! and sealing, expiry and wakeups, rendezvous sessions, TLS, and the auth,
+//! rate limiting and abuse checks the handlers in the binary apply.
+
+pub mod auth;
+pub mod clock;
+pub mod config;
+pub mod expiry;
+pub mod jwt;
+pub mod lockout;
+pub mod pow;
+pub mod proxy;
+pub mod ratelimit;
+pub mod rendezvous;
+pub mod seal;
+pub mod store;
+pub mod tls;
+pub mod token;
+pub mod waiters;
+
+/// How long a pin lives when it does not ask for a lifetime of its own.
+pub const DEFAULT_TTL_SECS: u32 = 10 * 60;
@@ -0,0 +1,248 @@
+//! Spots clients guessing pins, by counting their requests for pins that do
+//! not exist, and slows them down and then bans them from the namespace.
+
+use chrono::prelude::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The delay for the first miss past `delay_after`, doubling with each one after.
+const FIRST_DELAY: Duration = Duration::from_millis(250);
+const MAX_DELAY: Duration = Duration::from_secs(8);
+/// Repeat offenders are banned for twice as long each time, up to this.
+const MAX_BAN_SECS: i64 = 24 * 60 * 60;
+
+/// How many misses a client gets before it is slowed down and then banned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// Misses before each further one is answered late.
+    pub delay_after: u32,
+    /// Misses that get the client banned.
+    pub ban_after: u32,
+    /// Misses are forgotten once the client has gone this long without one.
+    pub window_secs: u32,
+    /// How long a first ban lasts.
+    pub ban_secs: u32,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            delay_after: 10,
+            ban_after: 30,
+            window_secs: 10 * 60,
+            ban_secs: 15 * 60,
+        }
+    }
+}
+
+/// What a miss costs the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Penalty {
+    None,
+    /// Answer the request only after this long.
+    Delay(Duration),
+    /// Refuse the client's requests to the namespace until then.
+    Ban(DateTime<Utc>),
+}
+
+/// A client currently banned from a namespace.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Ban {
+    /// None for requests whose address is unknown, which are counted together.
+    pub client: Option<IpAddr>,
+    pub namespace: String,
+    pub until: DateTime<Utc>,
+    /// How many times the client has been banned from the namespace in a row.
+    pub count: u32,
+}
+
+struct Record {
+    misses: u32,
+    last_miss_at: DateTime<Utc>,
+    banned_until: Option<DateTime<Utc>>,
+    bans: u32,
+}
+
+/// Misses and bans per client and namespace.
+pub struct Lockout {
+    policy: LockoutPolicy,
+    records: DashMap<(Option<IpAddr>, String), Record>,
+}
+
+impl Lockout {
+    pub fn new(policy: LockoutPolicy) -> Self {
+        Lockout {
+            policy,
+            records: DashMap::new(),
+        }
+    }
+
+    /// When the client's ban from the namespace ends, if it is banned.
+    pub fn banned_until(
+        &self,
+        client: Option<IpAddr>,
+        namespace: &str,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let record = self.records.get(&(client, namespace.to_string()))?;
+        record.banned_until.filter(|&until| until > now)
+    }
+
+    /// Counts a request for a pin that does not exist, saying what it costs.
+    pub fn record_miss(
+        &self,
+        client: Option<IpAddr>,
+        namespace: &str,
+        now: DateTime<Utc>,
+    ) -> Penalty {
+        let window = chrono::Duration::seconds(self.policy.window_secs.into());
+        let mut record = self
+            .records
+            .entry((client, namespace.to_string()))
+            .or_insert(Record {
+                misses: 0,
+                last_miss_at: now,
+                banned_until: None,
+                bans: 0,
+            });
+        if record.last_miss_at + window <= now {
+            record.misses = 0;
+        }
+        record.misses += 1;
+        record.last_miss_at = now;
+
+        if record.misses >= self.policy.ban_after {
+            let ban_secs = i64::from(self.policy.ban_secs)
+                .saturating_mul(1 << record.bans.min(16))
+                .min(MAX_BAN_SECS);
+            let until = now + chrono::Duration::seconds(ban_secs);
+            record.misses = 0;
+            record.bans += 1;
+            record.banned_until = Some(until);
+            Penalty::Ban(until)
+        } else if record.misses > self.policy.delay_after {
+            let doublings = (record.misses - self.policy.delay_after - 1).min(16);
+            Penalty::Delay((FIRST_DELAY * (1 << doublings)).min(MAX_DELAY))
+        } else {
+            Penalty::None
+        }
+    }
+
+    /// Everyone banned at `now`.
+    pub fn bans(&self, now: DateTime<Utc>) -> Vec<Ban> {
+        let mut bans: Vec<Ban> = self
+            .records
+            .iter()
+            .filter_map(|entry| {
+                let (client, namespace) = entry.key();
+                let until = entry.banned_until.filter(|&until| until > now)?;
+                Some(Ban {
+                    client: *client,
+                    namespace: namespace.clone(),
+                    until,
+                    count: entry.bans,
+                })
+            })
+            .collect();
+        bans.sort_by(|a, b| (&a.namespace, a.client).cmp(&(&b.namespace, b.client)));
+        bans
+    }
+
+    /// Forgets clients that are not banned and have not missed for a whole
+    /// window, so a later ban starts again from the shortest.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        let window = chrono::Duration::seconds(self.policy.window_secs.into());
+        self.records.retain(|_, record| {
+            let quiet_since = record
+                .banned_until
+                .map_or(record.last_miss_at, |until| until.max(record.last_miss_at));
+            quiet_since + window > now
+        });
+    }
+
+    /// Number of clients being tracked, across namespaces.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout() -> Lockout {
+        Lockout::new(LockoutPolicy {
+            delay_after: 2,
+            ban_after: 5,
+            window_secs: 60,
+            ban_secs: 100,
+        })
+    }
+
+    #[test]
+    fn test_misses_escalate_to_a_ban() {
+        let lockout = lockout();
+        let client = Some("10.0.0.1".parse().unwrap());
+        let now = Utc::now();
+        let penalties: Vec<_> = (0..5)
+            .map(|_| lockout.record_miss(client, "ns", now))
+            .collect();
+        let until = now + chrono::Duration::seconds(100);
+        assert_eq!(
+            penalties,
+            [
+                Penalty::None,
+                Penalty::None,
+                Penalty::Delay(Duration::from_millis(250)),
+                Penalty::Delay(Duration::from_millis(500)),
+                Penalty::Ban(until),
+            ]
+        );
+        assert_eq!(lockout.banned_until(client, "ns", now), Some(until));
+        assert_eq!(lockout.banned_until(client, "other", now), None);
+        assert_eq!(
+            lockout.bans(now),
+            [Ban {
+                client,
+                namespace: "ns".to_string(),
+                until,
+                count: 1,
+            }]
+        );
+        assert_eq!(lockout.banned_until(client, "ns", until), None);
+
+        // Straight back to guessing after the ban earns a longer one.
+        for _ in 0..4 {
+            lockout.record_miss(client, "ns", until);
+        }
+        assert_eq!(
+            lockout.record_miss(client, "ns", until),
+            Penalty::Ban(until + chrono::Duration::seconds(200))
+        );
+    }
+
+    #[test]
+    fn test_misses_are_forgotten_after_the_window() {
+        let lockout = lockout();
+        let client = Some("10.0.0.1".parse().unwrap());
+        let now = Utc::now();
+        for _ in 0..4 {
+            lockout.record_miss(client, "ns", now);
+        }
+        let later = now + chrono::Duration::seconds(60);
+        assert_eq!(lockout.record_miss(client, "ns", later), Penalty::None);
+        assert_eq!(lockout.record_miss(None, "ns", later), Penalty::None);
+        assert_eq!(lockout.len(), 2);
+
+        lockout.prune(later + chrono::Duration::seconds(59));
+        assert_eq!(lockout.len(), 2);
+        lockout.prune(later + chrono::Duration::seconds(60));
+        assert!(lockout.is_empty());
+    }
+}
@@ -1,181 +1,1144 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Json},
-    routing::{get, post, put},
-    Router,
+    extract::{
+        rejection::FormRejection,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Form, FromRef, FromRequestParts, Path, Query, Request, State,
+    },
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+    routing::{delete, get, post, put},
+    Extension, Router,
 };
 use chrono::prelude::{DateTime, Utc};
-use chrono::Duration;
-use clokwerk::{Scheduler, TimeUnits};
-use log::info;
+use configgymajiggy::auth::{ApiKeys, Denied, Grant, Scope};
+use configgymajiggy::clock::{Clock, SystemClock};
+use configgymajiggy::config::Config;
+use configgymajiggy::expiry::Expiry;
+use configgymajiggy::jwt::{self, JwtVerifier};
+use configgymajiggy::lockout::{Lockout, Penalty};
+use configgymajiggy::pow::ProofOfWork;
+use configgymajiggy::ratelimit::{Operation, RateLimiter};
+use configgymajiggy::rendezvous::{Rendezvous, Role, Seat};
+use configgymajiggy::seal::{Keyring, Plaintext};
+use configgymajiggy::store::{self, PinItem, PinState, PinStore};
+use configgymajiggy::tls::{Peer, TlsConfig, TlsListener};
+use configgymajiggy::token;
+use configgymajiggy::waiters::{Subscription, Waiters};
+use log::{error, info, warn};
 use rand::distr::Alphanumeric;
 use rand::{rng, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
-use std::sync::{Arc, Mutex};
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
+use zeroize::Zeroizing;
 
 const PIN_LENGTH: usize = 4;
 const MAX_RESULT_SIZE_BYTES: usize = 3000;
-const STALE_AGE_MINS: i64 = 10;
+const CREATOR_TOKEN_HEADER: &str = "x-creator-token";
+const RECEIVER_SECRET_HEADER: &str = "x-receiver-secret";
+const PROOF_OF_WORK_HEADER: &str = "x-proof-of-work";
+const API_KEY_HEADER: &str = "x-api-key";
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// How much longer a device must wait each time it polls too soon (RFC 8628, section 3.5).
+const SLOW_DOWN_SECS: u32 = 5;
+/// How often an event stream says it is still there while nothing happens.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Random pins looked up to estimate how full a namespace is.
+const OCCUPANCY_SAMPLES: u32 = 32;
+/// How often the TLS certificate files are checked for renewals.
+const TLS_CHECK_PERIOD: Duration = Duration::from_secs(10);
+/// How long shutdown waits for open requests before saving the store anyway,
+/// well inside the ten seconds `docker stop` allows.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How often waits look at a shared store again, since changes other replicas
+/// make to it never wake this one.
+const SHARED_STORE_RECHECK: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 struct BiboopState {
-    read: evmap::ReadHandle<String, PinItem>,
-    write: Arc<Mutex<evmap::WriteHandle<String, PinItem>>>,
+    store: Arc<dyn PinStore>,
+    expiry: Arc<Expiry>,
+    clock: Arc<dyn Clock>,
+    config: Arc<Config>,
+    /// Long polls and event streams waiting for their pin to change.
+    waiters: Arc<Waiters>,
+    /// WebSocket sessions between the two parties to a pin.
+    rendezvous: Arc<Rendezvous>,
+    rate_limiter: Arc<RateLimiter>,
+    /// Clients caught guessing pins.
+    lockout: Arc<Lockout>,
+    /// Challenges for creating pins, when proof of work is required.
+    pow: Arc<ProofOfWork>,
+    /// Keys every request must present. Anyone may use the service when unset.
+    api_keys: Option<Arc<ApiKeys>>,
+    /// Checks bearer JWTs, when a JWKS is configured.
+    jwt: Option<Arc<JwtVerifier>>,
+    /// Seals results before they are stored, and opens them for the receiver.
+    keyring: Arc<Keyring>,
+    /// Cancelled once the server starts shutting down, so that long polls,
+    /// event streams and rendezvous sessions let go instead of holding it open.
+    shutdown: CancellationToken,
 }
 
-// Need to implement Sync manually since evmap::ReadHandle contains Cell<()> 
-// which is not Sync, but in practice it's safe in our usage
-unsafe impl Sync for BiboopState {}
+/// Who a request was let through as.
+#[derive(Clone)]
+enum Caller {
+    /// Anyone at all, since no API keys or JWKS are configured.
+    Anonymous,
+    /// The holder of `ADMIN_TOKEN`.
+    Admin,
+    /// The holder of an API key or JWT.
+    Granted(Arc<Grant>),
+}
+
+/// The other end of a request's connection, when the server was started with it.
+async fn peer<S: Send + Sync>(parts: &mut Parts, state: &S) -> Option<Peer> {
+    let ConnectInfo(peer) = ConnectInfo::<Peer>::from_request_parts(parts, state).await.ok()?;
+    Some(peer)
+}
+
+/// The address a request came from, when the server was started with it.
+/// Behind a trusted proxy, that is the client the proxy forwarded it for.
+struct ClientIp(Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp
+where
+    BiboopState: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(peer) = peer(parts, state).await else {
+            return Ok(ClientIp(None));
+        };
+        let proxies = &BiboopState::from_ref(state).config.trusted_proxies;
+        if proxies.is_empty() {
+            return Ok(ClientIp(Some(peer.addr.ip())));
+        }
+        let forwarded_for: Vec<&str> = parts.headers.get_all(FORWARDED_FOR_HEADER).iter().filter_map(|v| v.to_str().ok()).collect();
+        Ok(ClientIp(Some(proxies.client_ip(peer.addr.ip(), &forwarded_for.join(",")))))
+    }
+}
+
+impl FromRef<(BiboopState, Operation)> for BiboopState {
+    fn from_ref((state, _): &(BiboopState, Operation)) -> Self {
+        state.clone()
+    }
+}
+
+/// Whether a request came with a certificate from the client CA.
+struct ClientCertified(bool);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientCertified {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientCertified(peer(parts, state).await.is_some_and(|peer| peer.client_certified)))
+    }
+}
+
+fn describe_client(client: Option<IpAddr>) -> String {
+    client.map_or_else(|| "unknown client".to_string(), |ip| ip.to_string())
+}
 
 #[derive(Serialize, Deserialize)]
 struct PinResponse {
     pin: String,
-    result: Option<HashMap<String, Value>>,
+    result: Option<Plaintext>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    /// How many times the receiver has extended the pin.
+    renewals: u32,
+    /// Lets clients count down to `expires_at` even if their own clock is off.
+    server_time: DateTime<Utc>,
+    /// Proves the caller created the pin. Only sent when the pin is issued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator_token: Option<String>,
+    /// Needed to collect the result, which the short pin alone cannot do.
+    /// Only sent when the pin is issued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiver_secret: Option<String>,
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize, Clone)]
-struct PinItem {
-    timestamp: DateTime<Utc>,
-    pin: String,
-    result: Option<HashMap<String, Value>>,
+impl PinResponse {
+    /// Describes `item`, with its `result` already opened.
+    fn new(item: PinItem, result: Option<Plaintext>, now: DateTime<Utc>) -> Self {
+        PinResponse {
+            expires_at: item.expires_at,
+            created_at: item.created_at,
+            renewals: item.renewals,
+            pin: item.pin,
+            result,
+            server_time: now,
+            creator_token: None,
+            receiver_secret: None,
+        }
+    }
+}
+
+/// The result `item` holds, if any, opened for handing to the receiver.
+fn open_result(namespace: &str, item: &PinItem, keyring: &Keyring) -> anyhow::Result<Option<Plaintext>> {
+    item.result.as_ref().map(|sealed| keyring.open(namespace, &item.pin, sealed)).transpose()
+}
+
+/// A freshly issued pin, with the token its creator can revoke it with and the
+/// secret its receiver collects the result with.
+struct NewPin {
+    item: PinItem,
+    creator_token: String,
+    receiver_secret: String,
+}
+
+#[derive(Deserialize)]
+struct CreatePinParams {
+    /// Requested lifetime in seconds, clamped to the configured limits.
+    ttl: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct PollParams {
+    /// Lifetime of the replacement pin, if one is issued.
+    ttl: Option<u32>,
+    /// Answer 410 Gone or 404 instead of issuing a replacement pin.
+    #[serde(default)]
+    strict: bool,
+    /// Seconds to hold the request open for data to arrive, capped at `MAX_WAIT_SECS`.
+    wait: Option<u32>,
 }
 
-impl evmap::ShallowCopy for PinItem {
-    unsafe fn shallow_copy(&self) -> std::mem::ManuallyDrop<Self> {
-        std::mem::ManuallyDrop::new(self.clone())
+#[derive(Deserialize)]
+struct SecretParams {
+    /// The receiver secret, for clients such as `EventSource` and browser
+    /// WebSockets that cannot set headers.
+    secret: Option<String>,
+}
+
+/// The receiver secret a request presents, in its header or its query.
+fn presented_secret<'a>(headers: &'a HeaderMap, params: &'a SecretParams) -> Option<&'a str> {
+    headers
+        .get(RECEIVER_SECRET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .or(params.secret.as_deref())
+}
+
+fn storage_error(e: anyhow::Error) -> Response {
+    error!("Storage error: {:#}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Storage error.").into_response()
+}
+
+fn not_found() -> Response {
+    (StatusCode::NOT_FOUND, "Pin not found.").into_response()
+}
+
+/// Why a pin can no longer be used, if it has ended.
+fn gone_reason(pin_state: PinState) -> Option<&'static str> {
+    match pin_state {
+        PinState::Consumed => Some("Pin has already been consumed."),
+        PinState::Expired => Some("Pin has expired."),
+        PinState::Revoked => Some("Pin has been revoked."),
+        PinState::AwaitingData | PinState::Fulfilled => None,
     }
 }
 
-#[allow(clippy::derive_hash_xor_eq)]
-impl Hash for PinItem {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.timestamp.hash(state);
-        self.pin.hash(state);
+/// Turns away callers without a live pin's receiver secret, which anything
+/// that hands over the result requires.
+fn refuse_receiver(item: &PinItem, secret: Option<&str>, now: DateTime<Utc>) -> Option<Response> {
+    // Ended pins have no result left to protect.
+    if !item.is_live(now) {
+        return None;
+    }
+    match secret {
+        // A pin nobody was given a secret for can never be collected.
+        _ if item.receiver_secret_hash.is_empty() => Some((StatusCode::FORBIDDEN, "Pin has no receiver.").into_response()),
+        None => Some((StatusCode::UNAUTHORIZED, "Receiver secret required.").into_response()),
+        Some(secret) if token::matches(secret, &item.receiver_secret_hash) => None,
+        Some(_) => Some((StatusCode::FORBIDDEN, "Invalid receiver secret.").into_response()),
     }
 }
 
-impl PinItem {
-    fn new(pin: String, result: Option<HashMap<String, Value>>) -> Self {
-        PinItem {
-            timestamp: Utc::now(),
-            pin,
-            result,
+/// Counts a request for a pin that does not exist against the client, holding
+/// the response back or banning the client once it looks like guessing.
+async fn record_miss(client: Option<IpAddr>, namespace: &str, state: &BiboopState) {
+    match state.lockout.record_miss(client, namespace, state.clock.now()) {
+        Penalty::None => {}
+        Penalty::Delay(delay) => {
+            info!("Delaying {} by {:?} for guessing pins in {:?}", describe_client(client), delay, namespace);
+            tokio::time::sleep(delay).await;
+        }
+        Penalty::Ban(until) => {
+            warn!("Banned {} from {:?} until {} for guessing pins", describe_client(client), namespace, until);
         }
     }
 }
 
-// Helper function to create consistent keys
-fn create_key(namespace: &str, pin: &str) -> String {
-    format!("{}:{}", namespace, pin)
+/// Says why a pin that is still remembered by its tombstone can no longer be used.
+fn gone(pin_state: PinState) -> Response {
+    match gone_reason(pin_state) {
+        Some(reason) => (StatusCode::GONE, reason).into_response(),
+        None => not_found(),
+    }
 }
 
-fn create_unique_pin(namespace: &str, state: &BiboopState) -> Option<String> {
-    for _ in 0..10 {
-        let pin: String = rng()
-            .sample_iter(&Alphanumeric)
-            .take(PIN_LENGTH)
-            .map(char::from)
-            .collect::<String>()
-            .to_uppercase();
-        
-        let key = create_key(namespace, &pin);
-
-        if !state.read.contains_key(&key) {
-            if let Ok(mut write_handle) = state.write.lock() {
-                write_handle.insert(key, PinItem::new(pin.clone(), None));
-                write_handle.refresh();
-                return Some(pin);
+fn random_pin() -> String {
+    rng()
+        .sample_iter(&Alphanumeric)
+        .take(PIN_LENGTH)
+        .map(char::from)
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// Roughly what share of the namespace's pins are taken, by looking up a
+/// sample of random ones.
+fn occupancy(namespace: &str, state: &BiboopState) -> anyhow::Result<f64> {
+    let mut taken = 0;
+    for _ in 0..OCCUPANCY_SAMPLES {
+        if state.store.get(namespace, &random_pin())?.is_some() {
+            taken += 1;
+        }
+    }
+    Ok(f64::from(taken) / f64::from(OCCUPANCY_SAMPLES))
+}
+
+/// What a request that may create a pin offers in place of proof of work.
+#[derive(Clone, Copy)]
+enum Proof<'a> {
+    /// Callers with an API key or JWT are trusted to create pins.
+    Authenticated,
+    Stamp(Option<&'a str>),
+}
+
+impl<'a> Proof<'a> {
+    fn of(headers: &'a HeaderMap, caller: &Caller) -> Self {
+        match caller {
+            Caller::Granted(_) => Proof::Authenticated,
+            Caller::Anonymous | Caller::Admin => {
+                Proof::Stamp(headers.get(PROOF_OF_WORK_HEADER).and_then(|v| v.to_str().ok()))
             }
         }
     }
-    None
 }
 
-fn create_new_pin_response(namespace: &str, state: &BiboopState) -> Option<PinResponse> {
-    let unique_pin = create_unique_pin(namespace, state)?;
-    Some(PinResponse {
-        pin: unique_pin,
-        result: None,
-    })
+/// Turns away pin creation without a solved challenge, when one is required.
+/// Checked before anything is written, so refused requests cost the store nothing.
+fn refuse_unproven(namespace: &str, proof: Proof, state: &BiboopState) -> Option<Response> {
+    if !state.pow.is_enabled() {
+        return None;
+    }
+    match proof {
+        Proof::Authenticated => None,
+        Proof::Stamp(None) => Some((StatusCode::PRECONDITION_REQUIRED, "Proof of work required.").into_response()),
+        Proof::Stamp(Some(stamp)) if state.pow.verify(namespace, stamp, state.clock.now()) => None,
+        Proof::Stamp(Some(_)) => Some((StatusCode::FORBIDDEN, "Invalid proof of work.").into_response()),
+    }
+}
+
+fn create_unique_pin(
+    namespace: &str,
+    ttl: Option<u32>,
+    state: &BiboopState,
+) -> anyhow::Result<Option<NewPin>> {
+    let ttl_secs = state.config.ttl.resolve(namespace, ttl);
+    let creator_token = token::generate();
+    let receiver_secret = token::generate();
+    for _ in 0..10 {
+        let pin = random_pin();
+        let mut item = PinItem::new(pin, None, state.clock.now()).with_ttl(ttl_secs);
+        item.creator_token_hash = Some(token::hash(&creator_token));
+        item.receiver_secret_hash = token::hash(&receiver_secret);
+        item.tombstone_secs = state.config.ttl.tombstone_secs;
+        item.poll_interval_secs = state.config.device_poll_interval_secs;
+        if state.store.create_if_absent(namespace, item.clone())? {
+            state.expiry.schedule(namespace, &item);
+            return Ok(Some(NewPin {
+                item,
+                creator_token,
+                receiver_secret,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+fn create_new_pin_response(
+    namespace: &str,
+    ttl: Option<u32>,
+    state: &BiboopState,
+) -> anyhow::Result<Option<PinResponse>> {
+    let Some(new_pin) = create_unique_pin(namespace, ttl, state)? else {
+        return Ok(None);
+    };
+    let mut response = PinResponse::new(new_pin.item, None, state.clock.now());
+    response.creator_token = Some(new_pin.creator_token);
+    response.receiver_secret = Some(new_pin.receiver_secret);
+    Ok(Some(response))
+}
+
+fn create_pin_http_response(namespace: &str, ttl: Option<u32>, proof: Proof, state: &BiboopState) -> Response {
+    if let Some(refused) = refuse_unproven(namespace, proof, state) {
+        return refused;
+    }
+    match create_new_pin_response(namespace, ttl, state) {
+        Ok(Some(res)) => Json(res).into_response(),
+        Ok(None) => (StatusCode::TOO_MANY_REQUESTS, "Could not find a free pin soon enough.").into_response(),
+        Err(e) => storage_error(e),
+    }
 }
 
-fn create_pin_http_response(namespace: &str, state: &BiboopState) -> impl IntoResponse {
-    let pin_response = create_new_pin_response(namespace, state);
-    match pin_response {
-        Some(res) => Json(res).into_response(),
-        _ => (StatusCode::TOO_MANY_REQUESTS, "Could not find a free pin soon enough.").into_response(),
+/// A pin as a poll found it, with the result the poll took, opened.
+struct Taken {
+    item: PinItem,
+    result: Option<Plaintext>,
+}
+
+/// Counts a poll and hands over the result if there is one. The result is
+/// opened before the pin is consumed, so one that cannot be opened stays put
+/// rather than being lost. Pins that have already ended come back unchanged,
+/// for the caller to check.
+fn take_pin_if_populated(
+    namespace: &str,
+    pin: &str,
+    now: DateTime<Utc>,
+    state: &BiboopState,
+) -> anyhow::Result<Option<Taken>> {
+    let opened = match state.store.get(namespace, pin)? {
+        Some(item) if item.is_live(now) => item.result.clone().zip(open_result(namespace, &item, &state.keyring)?),
+        _ => None,
+    };
+    let Some(item) = state.store.take_if_populated(namespace, pin, now)? else {
+        return Ok(None);
+    };
+    if item.result.is_some() {
+        // Consuming the pin starts its tombstone, which moves its purge time.
+        let mut consumed = item.clone();
+        consumed.consume(now);
+        state.expiry.schedule(namespace, &consumed);
     }
+    let result = match opened {
+        _ if !item.is_live(now) || item.result.is_none() => None,
+        Some((sealed, result)) if item.result.as_ref() == Some(&sealed) => Some(result),
+        // Answered again since we looked, so sealed just now with our key.
+        _ => open_result(namespace, &item, &state.keyring)?,
+    };
+    Ok(Some(Taken { item, result }))
 }
 
-fn get_and_remove_pin_if_populated(
+/// What became of an attempt to change a live pin.
+enum Change {
+    Applied(Box<PinItem>),
+    /// The pin exists, but the change does not apply to it.
+    Declined,
+    /// The pin is consumed, expired or revoked.
+    Ended(PinState),
+    NotFound,
+}
+
+/// Applies `change` to a pin if it is still live at `now`, rescheduling its
+/// purge if that moved. `change` returns false to leave the pin alone.
+fn change_pin(
     namespace: &str,
     pin: &str,
+    now: DateTime<Utc>,
     state: &BiboopState,
-) -> Option<PinResponse> {
-    let key = create_key(namespace, pin);
-    let item = state.read.get_one(&key)?;
-    let pin_item = item.clone();
-    
-    if pin_item.result.is_some() {
-        if let Ok(mut write_handle) = state.write.lock() {
-            write_handle.empty(key);
-            write_handle.refresh();
-        }
-    }
-    
-    Some(PinResponse {
-        pin: pin.to_string(),
-        result: pin_item.result,
+    change: &mut dyn FnMut(&mut PinItem) -> bool,
+) -> anyhow::Result<Change> {
+    let mut ended = None;
+    let mut declined = false;
+    let mut purge_at = None;
+    let changed = state.store.update(namespace, pin, &mut |item| {
+        ended = Some(item.state(now)).filter(|_| !item.is_live(now));
+        purge_at = Some(item.purge_at());
+        declined = ended.is_none() && !change(item);
+        ended.is_none() && !declined
+    })?;
+    Ok(match changed {
+        Some(item) => {
+            if purge_at != Some(item.purge_at()) {
+                state.expiry.schedule(namespace, &item);
+            }
+            Change::Applied(Box::new(item))
+        }
+        None => match ended {
+            Some(pin_state) => Change::Ended(pin_state),
+            None if declined => Change::Declined,
+            None => Change::NotFound,
+        },
     })
 }
 
+fn update_pin_if_exists(
+    namespace: &str,
+    pin: &str,
+    result: Plaintext,
+    state: &BiboopState,
+) -> anyhow::Result<Change> {
+    let now = state.clock.now();
+    let sealed = state.keyring.seal(namespace, pin, &result)?;
+    let change = change_pin(namespace, pin, now, state, &mut |item| {
+        item.touch(now, state.config.ttl.max_lifetime_secs);
+        item.result = Some(sealed.clone());
+        true
+    })?;
+    if let Change::Applied(_) = change {
+        state.waiters.notify(namespace, pin);
+    }
+    Ok(change)
+}
+
+/// Pushes a pin's deadline back by its TTL, but never past `max_lifetime_secs`
+/// after it was created. Declined once the pin is at that limit.
+fn renew_pin(namespace: &str, pin: &str, state: &BiboopState) -> anyhow::Result<Change> {
+    let now = state.clock.now();
+    let max_lifetime = chrono::Duration::seconds(state.config.ttl.max_lifetime_secs.into());
+    let change = change_pin(namespace, pin, now, state, &mut |item| {
+        let deadline = (now + chrono::Duration::seconds(item.ttl_secs.into()))
+            .min(item.created_at + max_lifetime);
+        if deadline <= item.expires_at {
+            return false;
+        }
+        item.expires_at = deadline;
+        item.renewals += 1;
+        true
+    })?;
+    if let Change::Applied(_) = change {
+        state.waiters.notify(namespace, pin);
+    }
+    Ok(change)
+}
+
+/// Revokes a pin for the holder of its creator token. Declined for anyone else.
+fn revoke_pin(
+    namespace: &str,
+    pin: &str,
+    creator_token: &str,
+    state: &BiboopState,
+) -> anyhow::Result<Change> {
+    let now = state.clock.now();
+    let change = change_pin(namespace, pin, now, state, &mut |item| {
+        let is_creator = item
+            .creator_token_hash
+            .as_deref()
+            .is_some_and(|hash| token::matches(creator_token, hash));
+        if is_creator {
+            item.revoke(now);
+        }
+        is_creator
+    })?;
+    if let Change::Applied(_) = change {
+        // Receivers waiting on the pin hear about it straight away.
+        state.waiters.notify(namespace, pin);
+    }
+    Ok(change)
+}
+
 async fn get_pin(
     Path(namespace): Path<String>,
+    Query(params): Query<CreatePinParams>,
     State(state): State<BiboopState>,
+    Extension(caller): Extension<Caller>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    create_pin_http_response(&namespace, &state)
+    create_pin_http_response(&namespace, params.ttl, Proof::of(&headers, &caller), &state)
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChallengeResponse {
+    challenge: String,
+    /// Leading zero bits the solution's hash needs.
+    difficulty: u32,
+    expires_in: i64,
+}
+
+/// Hands out a challenge to solve before creating a pin, harder the fuller
+/// the namespace is.
+async fn challenge(Path(namespace): Path<String>, State(state): State<BiboopState>) -> Response {
+    if !state.pow.is_enabled() {
+        return (StatusCode::NOT_FOUND, "Proof of work is not enabled.").into_response();
+    }
+    let occupancy = match occupancy(&namespace, &state) {
+        Ok(occupancy) => occupancy,
+        Err(e) => return storage_error(e),
+    };
+    let now = state.clock.now();
+    let challenge = state.pow.issue(&namespace, occupancy, now);
+    Json(ChallengeResponse {
+        challenge: challenge.challenge,
+        difficulty: challenge.difficulty,
+        expires_in: (challenge.expires_at - now).num_seconds(),
+    })
+    .into_response()
 }
 
 async fn poll_pin(
+    Path((namespace, pin)): Path<(String, String)>,
+    Query(params): Query<PollParams>,
+    State(state): State<BiboopState>,
+    ClientIp(client): ClientIp,
+    Extension(caller): Extension<Caller>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let secret = headers.get(RECEIVER_SECRET_HEADER).and_then(|v| v.to_str().ok());
+    let proof = Proof::of(&headers, &caller);
+    match state.store.get(&namespace, &pin) {
+        Ok(Some(item)) => {
+            if let Some(refused) = refuse_receiver(&item, secret, state.clock.now()) {
+                return refused;
+            }
+        }
+        Ok(None) => record_miss(client, &namespace, &state).await,
+        Err(e) => return storage_error(e),
+    }
+
+    let wait_secs = params.wait.unwrap_or(0).min(state.config.max_wait_secs);
+    let give_up_at = tokio::time::Instant::now() + Duration::from_secs(wait_secs.into());
+    loop {
+        // Subscribe before looking, so an answer in between still wakes us.
+        let subscription = state.waiters.subscribe(&namespace, &pin);
+        let changed = subscription.changed();
+        let now = state.clock.now();
+        let taken = take_pin_if_populated(&namespace, &pin, now, &state);
+        let Ok(Some(Taken { item: pin_item, result })) = &taken else {
+            return poll_response(taken, now, &namespace, &params, proof, &state);
+        };
+        let wait = give_up_at.saturating_duration_since(tokio::time::Instant::now());
+        // Answer with what we have when shutting down, rather than keep the server from stopping.
+        if !pin_item.is_live(now) || result.is_some() || wait.is_zero() || state.shutdown.is_cancelled() {
+            return poll_response(taken, now, &namespace, &params, proof, &state);
+        }
+        // Whatever ends the wait, look again before answering, so a pin that
+        // expired or was answered meanwhile is reported as it is now.
+        let until_expiry = (pin_item.expires_at - now).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::timeout(wait.min(next_look(until_expiry, &state)), changed) => {}
+            _ = state.shutdown.cancelled() => {}
+        }
+    }
+}
+
+/// How long to wait for a pin to change before looking at it again.
+fn next_look(until_expiry: Duration, state: &BiboopState) -> Duration {
+    if state.store.is_shared() {
+        until_expiry.min(SHARED_STORE_RECHECK)
+    } else {
+        until_expiry
+    }
+}
+
+fn poll_response(
+    taken: anyhow::Result<Option<Taken>>,
+    now: DateTime<Utc>,
+    namespace: &str,
+    params: &PollParams,
+    proof: Proof,
+    state: &BiboopState,
+) -> Response {
+    match taken {
+        Ok(Some(taken)) if taken.item.is_live(now) => Json(PinResponse::new(taken.item, taken.result, now)).into_response(),
+        // A revoked pin was cancelled on purpose, so it is never quietly replaced.
+        Ok(Some(Taken { item, .. })) if params.strict || item.is_revoked() => gone(item.state(now)),
+        Ok(None) if params.strict => not_found(),
+        Ok(_) => create_pin_http_response(namespace, params.ttl, proof, state),
+        Err(e) => storage_error(e),
+    }
+}
+
+/// Everything about a pin except its result, for dashboards and support tooling.
+#[derive(Serialize, Deserialize)]
+struct StatusResponse {
+    pin: String,
+    state: PinState,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    /// When the pin was consumed, expired or revoked.
+    ended_at: Option<DateTime<Utc>>,
+    renewals: u32,
+    reads: u32,
+    server_time: DateTime<Utc>,
+}
+
+impl StatusResponse {
+    fn new(item: PinItem, now: DateTime<Utc>) -> Self {
+        StatusResponse {
+            state: item.state(now),
+            ended_at: item.ended_at(now),
+            created_at: item.created_at,
+            expires_at: item.expires_at,
+            renewals: item.renewals,
+            reads: item.reads,
+            pin: item.pin,
+            server_time: now,
+        }
+    }
+}
+
+async fn pin_status(
     Path((namespace, pin)): Path<(String, String)>,
     State(state): State<BiboopState>,
 ) -> impl IntoResponse {
-    match get_and_remove_pin_if_populated(&namespace, &pin, &state) {
-        Some(pin_item) => Json(pin_item).into_response(),
-        _ => create_pin_http_response(&namespace, &state).into_response(),
+    let now = state.clock.now();
+    match state.store.get(&namespace, &pin) {
+        Ok(Some(item)) => Json(StatusResponse::new(item, now)).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => storage_error(e),
+    }
+}
+
+fn sse_event(name: &str, data: impl Serialize) -> Event {
+    Event::default().event(name).json_data(data).unwrap_or_else(|e| {
+        error!("Failed to serialize {} event: {}", name, e);
+        Event::default().event(name)
+    })
+}
+
+/// Follows one pin on behalf of an event stream, woken by the same
+/// notifications as long polls.
+struct PinWatch {
+    namespace: String,
+    pin: String,
+    state: BiboopState,
+    subscription: Subscription,
+    /// The deadline last reported, to tell when the pin has been renewed.
+    expires_at: DateTime<Utc>,
+    finished: bool,
+}
+
+impl PinWatch {
+    /// Waits for the next event worth sending. None once the stream is over.
+    async fn next_event(&mut self) -> Option<Event> {
+        while !self.finished {
+            // Subscribe before looking, so a change in between still wakes us.
+            let changed = self.subscription.changed();
+            let now = self.state.clock.now();
+            let item = match self.state.store.get(&self.namespace, &self.pin) {
+                Ok(Some(item)) => item,
+                // Purged while we slept, so it ran out long ago.
+                Ok(None) => {
+                    self.finished = true;
+                    return Some(Event::default().event("expired").data("{}"));
+                }
+                Err(e) => {
+                    error!("Storage error: {:#}", e);
+                    self.finished = true;
+                    return None;
+                }
+            };
+
+            if !item.is_live(now) {
+                self.finished = true;
+                let name = match item.state(now) {
+                    PinState::Revoked => "revoked",
+                    PinState::Consumed => "consumed",
+                    _ => "expired",
+                };
+                return Some(sse_event(name, StatusResponse::new(item, now)));
+            }
+            if item.result.is_some() {
+                // Delivering the result consumes the pin, just like a poll.
+                match take_pin_if_populated(&self.namespace, &self.pin, now, &self.state) {
+                    Ok(Some(taken)) if taken.item.is_live(now) && taken.result.is_some() => {
+                        self.finished = true;
+                        return Some(sse_event("fulfilled", PinResponse::new(taken.item, taken.result, now)));
+                    }
+                    // Somebody else took it first; the next look reports that.
+                    Ok(_) => continue,
+                    Err(e) => {
+                        error!("Storage error: {:#}", e);
+                        self.finished = true;
+                        return None;
+                    }
+                }
+            }
+            if item.expires_at != self.expires_at {
+                self.expires_at = item.expires_at;
+                return Some(sse_event("renewed", StatusResponse::new(item, now)));
+            }
+
+            // Stores that expire pins themselves, or that other replicas share, never wake us, so keep time too.
+            let until_expiry = (item.expires_at - now).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = changed => {}
+                _ = tokio::time::sleep(next_look(until_expiry, &self.state)) => {}
+                // Clients reconnect to an event stream that ends, so just end it.
+                _ = self.state.shutdown.cancelled() => self.finished = true,
+            }
+        }
+        None
+    }
+}
+
+/// Streams what happens to a pin as Server-Sent Events, ending with the
+/// event that ends the pin.
+async fn pin_events(
+    Path((namespace, pin)): Path<(String, String)>,
+    Query(params): Query<SecretParams>,
+    State(state): State<BiboopState>,
+    headers: HeaderMap,
+) -> Response {
+    let now = state.clock.now();
+    let secret = presented_secret(&headers, &params);
+    let subscription = state.waiters.subscribe(&namespace, &pin);
+    let expires_at = match state.store.get(&namespace, &pin) {
+        Ok(Some(item)) if item.is_live(now) => match refuse_receiver(&item, secret, now) {
+            Some(refused) => return refused,
+            None => item.expires_at,
+        },
+        Ok(Some(item)) => return gone(item.state(now)),
+        Ok(None) => return not_found(),
+        Err(e) => return storage_error(e),
+    };
+    let watch = PinWatch {
+        namespace,
+        pin,
+        state,
+        subscription,
+        expires_at,
+        finished: false,
+    };
+    let events = futures_util::stream::unfold(watch, |mut watch| async move {
+        let event = watch.next_event().await?;
+        Some((Ok::<_, Infallible>(event), watch))
+    });
+    Sse::new(events)
+        .keep_alive(
+            KeepAlive::new()
+                .interval(HEARTBEAT_INTERVAL)
+                .event(Event::default().event("heartbeat").data("{}")),
+        )
+        .into_response()
+}
+
+/// Opens a WebSocket session between the two parties to a pin, e.g. so that
+/// paired devices can trade an offer, a reply and an acknowledgement. Whoever
+/// presents the receiver secret takes the receiver seat, and a party with just
+/// the pin the sender seat, as for collecting and answering the pin.
+async fn rendezvous(
+    Path((namespace, pin)): Path<(String, String)>,
+    Query(params): Query<SecretParams>,
+    State(state): State<BiboopState>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let now = state.clock.now();
+    let secret = presented_secret(&headers, &params);
+    let role = match state.store.get(&namespace, &pin) {
+        Ok(Some(item)) if item.is_live(now) => match secret {
+            None => Role::Sender,
+            Some(_) => match refuse_receiver(&item, secret, now) {
+                Some(refused) => return refused,
+                None => Role::Receiver,
+            },
+        },
+        Ok(Some(item)) => return gone(item.state(now)),
+        Ok(None) => return not_found(),
+        Err(e) => return storage_error(e),
+    };
+    let Some(seat) = state.rendezvous.join(&namespace, &pin, role) else {
+        let taken = match role {
+            Role::Sender => "The sender has already joined.",
+            Role::Receiver => "The receiver has already joined.",
+        };
+        return (StatusCode::CONFLICT, taken).into_response();
+    };
+    upgrade
+        // Cut off anything far past the limit before buffering it. Messages just
+        // past it still get a close frame saying why.
+        .max_message_size(MAX_RESULT_SIZE_BYTES * 4)
+        .on_upgrade(move |socket| relay(socket, seat, namespace, pin, state))
+}
+
+/// Relays messages between the two parties until either leaves or the pin ends.
+async fn relay(mut socket: WebSocket, mut seat: Seat, namespace: String, pin: String, state: BiboopState) {
+    let subscription = state.waiters.subscribe(&namespace, &pin);
+    loop {
+        // Subscribe before looking, so a change in between still wakes us.
+        let changed = subscription.changed();
+        let now = state.clock.now();
+        let until_expiry = match state.store.get(&namespace, &pin) {
+            Ok(Some(item)) if item.is_live(now) => (item.expires_at - now).to_std().unwrap_or_default(),
+            Ok(Some(item)) => {
+                let reason = gone_reason(item.state(now)).unwrap_or_default();
+                return close_session(socket, close_code::NORMAL, reason).await;
+            }
+            Ok(None) => return close_session(socket, close_code::NORMAL, "Pin has expired.").await,
+            Err(e) => {
+                error!("Storage error: {:#}", e);
+                return close_session(socket, close_code::ERROR, "Storage error.").await;
+            }
+        };
+
+        tokio::select! {
+            // Look at the pin first, so that when it ends both parties are told why,
+            // rather than one hearing only that the other left.
+            biased;
+            _ = changed => {}
+            // Stores that expire pins themselves, or that other replicas share, never wake us, so keep time too.
+            _ = tokio::time::sleep(next_look(until_expiry, &state)) => {}
+            _ = state.shutdown.cancelled() => {
+                return close_session(socket, close_code::AWAY, "Server is shutting down.").await;
+            }
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(text))) => {
+                    if text.len() > MAX_RESULT_SIZE_BYTES {
+                        return close_session(socket, close_code::SIZE, "Payload too large.").await;
+                    }
+                    if serde_json::from_str::<Value>(&text).is_err() {
+                        return close_session(socket, close_code::UNSUPPORTED, "Messages must be JSON.").await;
+                    }
+                    match seat.outbox.try_send(text.to_string()) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            return close_session(socket, close_code::POLICY, "Too many unread messages.").await;
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            return close_session(socket, close_code::NORMAL, "The other party has left.").await;
+                        }
+                    }
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    return close_session(socket, close_code::UNSUPPORTED, "Messages must be JSON.").await;
+                }
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                // This party has left, and dropping its seat tells the other.
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+            },
+            relayed = seat.inbox.recv() => match relayed {
+                Some(text) => {
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        return;
+                    }
+                }
+                None => return close_session(socket, close_code::NORMAL, "The other party has left.").await,
+            },
+        }
     }
 }
 
+async fn close_session(mut socket: WebSocket, code: u16, reason: &str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    // Nobody to tell if the party has already gone.
+    socket.send(Message::Close(Some(frame))).await.ok();
+}
+
 async fn respond_to_pin(
     Path((namespace, pin)): Path<(String, String)>,
     State(state): State<BiboopState>,
-    Json(result): Json<HashMap<String, Value>>,
+    ClientIp(client): ClientIp,
+    Json(result): Json<Plaintext>,
 ) -> impl IntoResponse {
     let serialized = match serde_json::to_string(&result) {
-        Ok(s) => s,
+        Ok(s) => Zeroizing::new(s),
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize data").into_response(),
     };
     if serialized.len() > MAX_RESULT_SIZE_BYTES {
         return (StatusCode::PAYLOAD_TOO_LARGE, "Payload too large.").into_response();
     }
 
-    let key = create_key(&namespace, &pin);
-    if state.read.contains_key(&key) {
-        if let Ok(mut write_handle) = state.write.lock() {
-            write_handle.update(
-                key,
-                PinItem::new(pin.to_string(), Some(result)),
-            );
-            write_handle.refresh();
+    match update_pin_if_exists(&namespace, &pin, result, &state) {
+        Ok(Change::Applied(_)) => (StatusCode::ACCEPTED, "Thanks!").into_response(),
+        Ok(Change::Ended(pin_state)) => gone(pin_state),
+        Ok(Change::Declined | Change::NotFound) => {
+            record_miss(client, &namespace, &state).await;
+            not_found()
         }
-        (StatusCode::ACCEPTED, "Thanks!").into_response()
-    } else {
-        (StatusCode::NOT_FOUND, "Pin not found.").into_response()
+        Err(e) => storage_error(e),
+    }
+}
+
+async fn revoke(
+    Path((namespace, pin)): Path<(String, String)>,
+    State(state): State<BiboopState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(creator_token) = headers.get(CREATOR_TOKEN_HEADER).and_then(|v| v.to_str().ok()) else {
+        return (StatusCode::UNAUTHORIZED, "Creator token required.").into_response();
+    };
+    match revoke_pin(&namespace, &pin, creator_token, &state) {
+        Ok(Change::Applied(_)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(Change::Declined) => (StatusCode::FORBIDDEN, "Invalid creator token.").into_response(),
+        Ok(Change::Ended(pin_state)) => gone(pin_state),
+        Ok(Change::NotFound) => not_found(),
+        Err(e) => storage_error(e),
+    }
+}
+
+async fn renew(
+    Path((namespace, pin)): Path<(String, String)>,
+    State(state): State<BiboopState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let secret = headers.get(RECEIVER_SECRET_HEADER).and_then(|v| v.to_str().ok());
+    match state.store.get(&namespace, &pin) {
+        Ok(Some(item)) => {
+            if let Some(refused) = refuse_receiver(&item, secret, state.clock.now()) {
+                return refused;
+            }
+        }
+        // Reported as not found below.
+        Ok(None) => {}
+        Err(e) => return storage_error(e),
+    }
+    match renew_pin(&namespace, &pin, &state) {
+        Ok(Change::Applied(item)) => match open_result(&namespace, &item, &state.keyring) {
+            Ok(result) => Json(PinResponse::new(*item, result, state.clock.now())).into_response(),
+            Err(e) => storage_error(e),
+        },
+        Ok(Change::Declined) => (StatusCode::CONFLICT, "Pin has reached its maximum lifetime.").into_response(),
+        Ok(Change::Ended(pin_state)) => gone(pin_state),
+        Ok(Change::NotFound) => not_found(),
+        Err(e) => storage_error(e),
+    }
+}
+
+/// What a device gets when it starts the device flow (RFC 8628 section 3.2).
+/// The user code is a pin, and the device code is that pin and its receiver secret.
+#[derive(Serialize, Deserialize)]
+struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: String,
+    expires_in: i64,
+    interval: u32,
+}
+
+/// A device polling for its token (RFC 8628 section 3.4).
+#[derive(Deserialize)]
+struct DeviceTokenRequest {
+    grant_type: Option<String>,
+    device_code: Option<String>,
+}
+
+/// An OAuth error (RFC 6749 section 5.2).
+#[derive(Serialize, Deserialize)]
+struct OAuthError {
+    error: String,
+    error_description: String,
+}
+
+fn oauth_error(error: &str, description: &str) -> Response {
+    let body = OAuthError {
+        error: error.to_string(),
+        error_description: description.to_string(),
+    };
+    (StatusCode::BAD_REQUEST, [(header::CACHE_CONTROL, "no-store")], Json(body)).into_response()
+}
+
+fn device_flow_disabled() -> Response {
+    (StatusCode::NOT_FOUND, "Device flow is not enabled.").into_response()
+}
+
+/// Starts a device flow by issuing a pin for the user to approve.
+async fn device_authorization(
+    Path(namespace): Path<String>,
+    State(state): State<BiboopState>,
+    Extension(caller): Extension<Caller>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(verification_uri) = state.config.device_verification_uri.clone() else {
+        return device_flow_disabled();
+    };
+    if let Some(refused) = refuse_unproven(&namespace, Proof::of(&headers, &caller), &state) {
+        return refused;
+    }
+    let new_pin = match create_unique_pin(&namespace, None, &state) {
+        Ok(Some(new_pin)) => new_pin,
+        Ok(None) => return (StatusCode::TOO_MANY_REQUESTS, "Could not find a free pin soon enough.").into_response(),
+        Err(e) => return storage_error(e),
+    };
+    let separator = if verification_uri.contains('?') { '&' } else { '?' };
+    Json(DeviceAuthorizationResponse {
+        device_code: format!("{}.{}", new_pin.item.pin, new_pin.receiver_secret),
+        verification_uri_complete: format!("{}{}user_code={}", verification_uri, separator, new_pin.item.pin),
+        verification_uri,
+        expires_in: (new_pin.item.expires_at - state.clock.now()).num_seconds(),
+        interval: state.config.device_poll_interval_secs,
+        user_code: new_pin.item.pin,
+    })
+    .into_response()
+}
+
+/// Answers a device polling for its token. Approving the user code with a PUT
+/// of the token response hands it over; a PUT of `{"error": "access_denied"}`
+/// denies it.
+async fn device_token(
+    Path(namespace): Path<String>,
+    State(state): State<BiboopState>,
+    request: Result<Form<DeviceTokenRequest>, FormRejection>,
+) -> Response {
+    if state.config.device_verification_uri.is_none() {
+        return device_flow_disabled();
+    }
+    let Ok(Form(request)) = request else {
+        return oauth_error("invalid_request", "Expected a form-encoded token request.");
+    };
+    if request.grant_type.as_deref() != Some(DEVICE_CODE_GRANT_TYPE) {
+        return oauth_error("unsupported_grant_type", "Only the device code grant is supported.");
+    }
+    let Some((pin, secret)) = request.device_code.as_deref().and_then(|code| code.split_once('.')) else {
+        return oauth_error("invalid_request", "A device code is required.");
+    };
+
+    let now = state.clock.now();
+    let item = match state.store.get(&namespace, pin) {
+        Ok(Some(item)) => item,
+        Ok(None) => return oauth_error("invalid_grant", "Unknown device code."),
+        Err(e) => return storage_error(e),
+    };
+    if !token::matches(secret, &item.receiver_secret_hash) {
+        return oauth_error("invalid_grant", "Unknown device code.");
+    }
+    if !item.is_live(now) {
+        return device_token_response(item, None, now);
+    }
+    let interval = chrono::Duration::seconds(item.poll_interval_secs.into());
+    if item.last_read_at.is_some_and(|at| now - at < interval) {
+        // The device is told to wait longer, so hold it to that.
+        let slowed = state.store.update(&namespace, pin, &mut |item| {
+            item.poll_interval_secs += SLOW_DOWN_SECS;
+            true
+        });
+        if let Err(e) = slowed {
+            return storage_error(e);
+        }
+        return oauth_error("slow_down", "Polling too often.");
+    }
+    match take_pin_if_populated(&namespace, pin, now, &state) {
+        Ok(Some(taken)) => device_token_response(taken.item, taken.result, now),
+        Ok(None) => oauth_error("invalid_grant", "Unknown device code."),
+        Err(e) => storage_error(e),
+    }
+}
+
+fn device_token_response(item: PinItem, result: Option<Plaintext>, now: DateTime<Utc>) -> Response {
+    match item.state(now) {
+        PinState::Expired => return oauth_error("expired_token", "The device code has expired."),
+        PinState::Revoked => return oauth_error("access_denied", "The request was cancelled."),
+        PinState::Consumed => return oauth_error("invalid_grant", "The device code has already been used."),
+        PinState::AwaitingData | PinState::Fulfilled => {}
+    }
+    match result {
+        None => oauth_error("authorization_pending", "The user has not approved the request yet."),
+        Some(result) if result.get("error") == Some(&Value::from("access_denied")) => {
+            oauth_error("access_denied", "The user denied the request.")
+        }
+        Some(result) => ([(header::CACHE_CONTROL, "no-store")], Json(result)).into_response(),
     }
 }
 
@@ -183,13 +1146,235 @@ async fn health() -> impl IntoResponse {
     "All good."
 }
 
-fn create_router() -> Router<BiboopState> {
+/// Charges a request to its client's budget for `operation` in the namespace,
+/// turning it away with 429 once the budget is spent.
+async fn rate_limit(
+    State((state, operation)): State<(BiboopState, Operation)>,
+    ClientIp(client): ClientIp,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let namespace = params.get("namespace").map(String::as_str).unwrap_or_default();
+    match state.rate_limiter.check(operation, client, namespace, state.clock.now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0).to_string();
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after)], "Too many requests.").into_response()
+        }
+    }
+}
+
+/// Turns away clients banned from the namespace for guessing pins.
+async fn refuse_banned(
+    State(state): State<BiboopState>,
+    ClientIp(client): ClientIp,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let namespace = params.get("namespace").map(String::as_str).unwrap_or_default();
+    let now = state.clock.now();
+    let Some(until) = state.lockout.banned_until(client, namespace, now) else {
+        return next.run(request).await;
+    };
+    info!("Refused {} in {:?}, banned until {}", describe_client(client), namespace, until);
+    let wait = (until - now).to_std().unwrap_or_default();
+    let retry_after = wait.as_secs_f64().ceil().max(1.0).to_string();
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after)],
+        "Too many requests for pins that do not exist.",
+    )
+        .into_response()
+}
+
+/// Turns away requests without a client certificate in namespaces that require one.
+async fn require_client_cert(
+    State(state): State<BiboopState>,
+    ClientIp(client): ClientIp,
+    ClientCertified(certified): ClientCertified,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let namespace = params.get("namespace").map(String::as_str).unwrap_or_default();
+    let required = state.config.tls.as_ref().is_some_and(|tls| tls.client_cert_namespaces.matches(namespace));
+    if certified || !required {
+        return next.run(request).await;
+    }
+    info!("Refused {} in {:?} without a client certificate", describe_client(client), namespace);
+    (StatusCode::FORBIDDEN, "Client certificate required.").into_response()
+}
+
+/// The API key, JWT or admin token sent as a bearer token, or as `X-Api-Key`.
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    bearer
+        .or_else(|| headers.get(API_KEY_HEADER).and_then(|v| v.to_str().ok()))
+        .map(str::to_string)
+}
+
+fn refuse_key(denied: Denied) -> Response {
+    let unauthorized = |challenge, reason| (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)], reason).into_response();
+    match denied {
+        Denied::Missing => unauthorized("Bearer", "Credentials required."),
+        Denied::Unknown => unauthorized("Bearer", "Invalid API key."),
+        Denied::Invalid => unauthorized(r#"Bearer error="invalid_token""#, "Invalid bearer token."),
+        Denied::Expired => unauthorized(r#"Bearer error="invalid_token""#, "Bearer token has expired."),
+        Denied::Scope => (StatusCode::FORBIDDEN, "Credentials are not allowed to do this.").into_response(),
+        Denied::Namespace => (StatusCode::FORBIDDEN, "Credentials are not allowed in this namespace.").into_response(),
+    }
+}
+
+/// Who presented `presented`, or None when neither API keys nor a JWKS are
+/// configured. Credentials shaped like a JWT are checked against the JWKS,
+/// anything else against the API keys.
+fn authenticate(state: &BiboopState, presented: Option<&str>) -> Option<Result<Arc<Grant>, Denied>> {
+    let bearer_jwt = presented.filter(|presented| jwt::looks_like_jwt(presented));
+    match (&state.jwt, &state.api_keys, bearer_jwt) {
+        (Some(jwt), _, Some(token)) => Some(jwt.verify(token, state.clock.now()).map(Arc::new)),
+        (_, Some(keys), _) => Some(keys.authenticate(presented)),
+        (Some(_), None, _) => Some(Err(presented.map_or(Denied::Missing, |_| Denied::Invalid))),
+        (None, None, _) => None,
+    }
+}
+
+/// Lets a request through only with an API key or JWT allowed to make it in
+/// its namespace, when either is configured. The admin endpoints also take
+/// `ADMIN_TOKEN`, and are off when none of them is configured.
+async fn authorize(
+    State((state, scope)): State<(BiboopState, Scope)>,
+    path: Option<Path<HashMap<String, String>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let namespace = path.as_ref().and_then(|Path(params)| params.get("namespace"));
+    let presented = presented_key(request.headers());
+    let admin_token = state.config.admin_token.as_deref().filter(|_| scope == Scope::Admin);
+    if let (Some(admin_token), Some(presented)) = (admin_token, presented.as_deref()) {
+        if token::matches(presented, &token::hash(admin_token)) {
+            request.extensions_mut().insert(Caller::Admin);
+            return next.run(request).await;
+        }
+    }
+    let caller = match (authenticate(&state, presented.as_deref()), admin_token) {
+        (Some(granted), _) => match granted.and_then(|grant| grant.check(scope, namespace.map(String::as_str)).map(|()| grant)) {
+            Ok(grant) => Caller::Granted(grant),
+            Err(denied) => {
+                info!("Refused {:?} request to {:?}: {:?}", scope, namespace, denied);
+                return refuse_key(denied);
+            }
+        },
+        (None, None) if scope == Scope::Admin => {
+            return (StatusCode::NOT_FOUND, "Admin API is not enabled.").into_response();
+        }
+        (None, Some(_)) if presented.is_none() => return (StatusCode::UNAUTHORIZED, "Admin token required.").into_response(),
+        (None, Some(_)) => return (StatusCode::FORBIDDEN, "Invalid admin token.").into_response(),
+        (None, None) => Caller::Anonymous,
+    };
+    request.extensions_mut().insert(caller);
+    next.run(request).await
+}
+
+/// Clients currently banned for guessing pins, in the namespaces the caller may administer.
+async fn list_bans(State(state): State<BiboopState>, Extension(caller): Extension<Caller>) -> impl IntoResponse {
+    let mut bans = state.lockout.bans(state.clock.now());
+    if let Caller::Granted(grant) = caller {
+        bans.retain(|ban| grant.covers(&ban.namespace));
+    }
+    Json(bans)
+}
+
+fn create_router(state: BiboopState) -> Router {
+    let authorize = |scope| middleware::from_fn_with_state((state.clone(), scope), authorize);
+    let guard = |operation| {
+        ServiceBuilder::new()
+            .layer(authorize(Scope::from(operation)))
+            .layer(middleware::from_fn_with_state((state.clone(), operation), rate_limit))
+    };
     Router::new()
+        .route("/pin/{namespace}", post(get_pin).layer(guard(Operation::Create)))
+        .route("/challenge/{namespace}", post(challenge).layer(guard(Operation::Create)))
+        .route("/pin/{namespace}/{pin}", get(pin_status).layer(guard(Operation::Poll)))
+        .route("/pin/{namespace}/{pin}", post(poll_pin).layer(guard(Operation::Poll)))
+        .route("/pin/{namespace}/{pin}", put(respond_to_pin).layer(guard(Operation::Submit)))
+        .route("/pin/{namespace}/{pin}", delete(revoke).layer(guard(Operation::Create)))
+        .route("/pin/{namespace}/{pin}/renew", post(renew).layer(guard(Operation::Poll)))
+        .route("/pin/{namespace}/{pin}/events", get(pin_events).layer(guard(Operation::Poll)))
+        .route("/pin/{namespace}/{pin}/ws", get(rendezvous).layer(guard(Operation::Poll)))
+        .route("/device/{namespace}/authorize", post(device_authorization).layer(guard(Operation::Create)))
+        .route("/device/{namespace}/token", post(device_token).layer(guard(Operation::Poll)))
+        .route_layer(middleware::from_fn_with_state(state.clone(), refuse_banned))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_client_cert))
         .route("/health", get(health))
-        .route("/pin/{namespace}", post(get_pin))
-        .route("/pin/{namespace}/{pin}", post(poll_pin))
-        .route("/pin/{namespace}/{pin}", put(respond_to_pin))
+        .route("/admin/bans", get(list_bans).layer(authorize(Scope::Admin)))
         .layer(CorsLayer::permissive())
+        .with_state(state)
+}
+
+fn checkpoint(state: &BiboopState) {
+    if let Err(e) = state.store.checkpoint() {
+        error!("Failed to checkpoint the store: {:#}", e);
+    }
+}
+
+/// Runs `job` on the blocking pool every `period`, starting one period from now.
+fn spawn_periodic(period: Duration, job: impl Fn() + Send + Sync + 'static) {
+    let job = Arc::new(job);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let job = job.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || job()).await {
+                error!("Periodic job panicked: {}", e);
+            }
+        }
+    });
+}
+
+/// Reloads the TLS certificate whenever the process gets SIGHUP.
+#[cfg(unix)]
+async fn reload_tls_on_hangup(tls: Arc<TlsConfig>) {
+    let Ok(mut hangup) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) else {
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        match tls.reload() {
+            Ok(()) => info!("Reloaded the TLS certificate"),
+            Err(e) => error!("Failed to reload the TLS certificate, keeping the previous one: {:#}", e),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
 }
 
 #[tokio::main]
@@ -197,108 +1382,295 @@ async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
     env_logger::init();
 
-    let (read, write) = evmap::new::<String, PinItem>();
+    let config = Config::from_env()?;
+
+    let api_keys = match &config.api_keys_path {
+        Some(path) => {
+            let keys = ApiKeys::load(path)?;
+            info!("Loaded {} API keys from {}", keys.len(), path.display());
+            Some(Arc::new(keys))
+        }
+        None => None,
+    };
+    let jwt = match &config.jwt {
+        Some(settings) => {
+            let verifier = JwtVerifier::new(settings.clone())?;
+            info!("Accepting JWTs from {} signed with {} keys", settings.issuer, verifier.key_count());
+            Some(Arc::new(verifier))
+        }
+        None => None,
+    };
+
+    // Config refuses a missing key wherever results outlive this process.
+    let keyring = config.master_keys.clone().unwrap_or_else(Keyring::generate);
+    info!("Sealing results with master key {}", keyring.current_id());
+
+    let tls = match &config.tls {
+        Some(settings) => {
+            let tls = TlsConfig::load(settings.clone())?;
+            info!("Serving TLS with the certificate in {}", settings.cert_path.display());
+            Some(Arc::new(tls))
+        }
+        None => None,
+    };
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let store = store::open(&config, clock.clone())?;
+    let waiters = Arc::new(Waiters::new());
+    let expiry = Arc::new(Expiry::new(store.clone(), clock.clone(), waiters.clone()));
+    expiry.schedule_existing()?;
+    tokio::spawn(expiry.clone().run());
     let state = BiboopState {
-        read,
-        write: Arc::new(Mutex::new(write)),
+        store,
+        expiry,
+        clock,
+        config: Arc::new(config.clone()),
+        waiters,
+        rendezvous: Arc::new(Rendezvous::new()),
+        rate_limiter: Arc::new(RateLimiter::new(config.rate_limits, config.namespace_rate_limits)),
+        lockout: Arc::new(Lockout::new(config.lockout)),
+        pow: Arc::new(ProofOfWork::new(config.pow)),
+        api_keys,
+        jwt,
+        keyring: Arc::new(keyring),
+        shutdown: CancellationToken::new(),
     };
 
-    let mut scheduler = Scheduler::with_tz(chrono::Utc);
     let clone_state = state.clone();
-    scheduler.every(10.seconds()).run(move || {
-        let mut keys_to_remove: Vec<String> = Vec::new();
-        if let Some(items) = &clone_state.read.read() {
-            for (key, pin_items) in items {
-                if let Some(pin_item) = pin_items.get_one() {
-                    let age = Utc::now().signed_duration_since(pin_item.timestamp);
-                    if age > Duration::minutes(STALE_AGE_MINS) {
-                        keys_to_remove.push(key.to_string())
-                    }
-                }
-            }
+    spawn_periodic(
+        Duration::from_secs(config.snapshot_interval_secs.into()),
+        move || checkpoint(&clone_state),
+    );
+    let clone_state = state.clone();
+    spawn_periodic(Duration::from_secs(1), move || {
+        if let Err(e) = clone_state.store.flush() {
+            error!("Failed to flush the store: {:#}", e);
         }
+    });
 
-        if !keys_to_remove.is_empty() {
-            if let Ok(mut write_handle) = clone_state.write.lock() {
-                for key in keys_to_remove {
-                    info!("Cleaning up stale key {}", key);
-                    write_handle.empty(key);
-                }
-                write_handle.refresh();
+    let clone_state = state.clone();
+    spawn_periodic(Duration::from_secs(60), move || {
+        let now = clone_state.clock.now();
+        clone_state.rate_limiter.prune(now);
+        clone_state.lockout.prune(now);
+        clone_state.pow.prune(now);
+    });
+
+    if let Some(jwt) = state.jwt.clone() {
+        spawn_periodic(Duration::from_secs(config.jwks_refresh_secs.into()), move || {
+            if let Err(e) = jwt.refresh() {
+                warn!("Failed to refresh the JWKS, keeping the previous keys: {:#}", e);
+            }
+        });
+    }
+
+    if let Some(tls) = tls.clone() {
+        #[cfg(unix)]
+        tokio::spawn(reload_tls_on_hangup(tls.clone()));
+        spawn_periodic(TLS_CHECK_PERIOD, move || match tls.reload_if_changed() {
+            Ok(true) => info!("Reloaded the renewed TLS certificate"),
+            Ok(false) => {}
+            Err(e) => error!("Failed to reload the TLS certificate, keeping the previous one: {:#}", e),
+        });
+    }
+
+    let app = create_router(state.clone()).into_make_service_with_connect_info::<Peer>();
+
+    let listener = tokio::net::TcpListener::bind(&config.bind_address).await?;
+    let shutdown = state.shutdown.clone();
+    let signal = async move {
+        shutdown_signal().await;
+        shutdown.cancel();
+    };
+    let serve = async {
+        match tls {
+            Some(tls) => {
+                info!("Server running on https://{}", config.bind_address);
+                axum::serve(TlsListener::new(listener, tls)?, app).with_graceful_shutdown(signal).await
+            }
+            None => {
+                info!("Server running on http://{}", config.bind_address);
+                axum::serve(listener, app).with_graceful_shutdown(signal).await
             }
         }
-    });
-    let _thread_handle = scheduler.watch_thread(std::time::Duration::from_millis(100));
+    };
+    let drain_deadline = async {
+        state.shutdown.cancelled().await;
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+    };
+    tokio::select! {
+        served = serve => served?,
+        _ = drain_deadline => warn!("Gave up waiting for open connections after {:?}", SHUTDOWN_GRACE),
+    }
 
-    let app = create_router().with_state(state);
-    
-    let bind_addr = std::env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    info!("Server running on http://{}", bind_addr);
-    axum::serve(listener, app).await?;
+    checkpoint(&state);
 
     Ok(())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use axum_test::TestServer;
+    use configgymajiggy::clock::ManualClock;
+    use axum::extract::connect_info::MockConnectInfo;
+    use configgymajiggy::jwt::{JwksSource, JwtSettings};
+    use configgymajiggy::tls::TlsSettings;
+    use configgymajiggy::lockout::LockoutPolicy;
+    use configgymajiggy::pow::{self, PowPolicy};
+    use configgymajiggy::ratelimit::RateLimits;
+    use configgymajiggy::DEFAULT_TTL_SECS;
     use serde_json::json;
 
-    fn create_test_state() -> BiboopState {
-        let (read, write) = evmap::new::<String, PinItem>();
+    pub(crate) fn create_test_state() -> BiboopState {
+        create_test_state_with_clock(Arc::new(SystemClock))
+    }
+
+    pub(crate) fn create_test_state_with_clock(clock: Arc<dyn Clock>) -> BiboopState {
+        let store: Arc<dyn PinStore> = Arc::new(store::MemoryStore::new());
+        let waiters = Arc::new(Waiters::new());
         BiboopState {
-            read,
-            write: Arc::new(Mutex::new(write)),
+            expiry: Arc::new(Expiry::new(store.clone(), clock.clone(), waiters.clone())),
+            store,
+            clock,
+            config: Arc::new(Config::default()),
+            waiters,
+            rendezvous: Arc::new(Rendezvous::new()),
+            rate_limiter: Arc::new(RateLimiter::new(Default::default(), RateLimits::OFF)),
+            lockout: Arc::new(Lockout::new(Default::default())),
+            pow: Arc::new(ProofOfWork::new(Default::default())),
+            api_keys: None,
+            jwt: None,
+            keyring: Arc::new(Keyring::generate()),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Polls a pin the way its receiver does, with the secret it was issued.
+    fn poll(server: &TestServer, url: &str, created: &PinResponse) -> axum_test::TestRequest {
+        server
+            .post(url)
+            .add_header(RECEIVER_SECRET_HEADER, created.receiver_secret.as_deref().unwrap())
+    }
+
+    /// Renews a pin the way its receiver does, with the secret it was issued.
+    fn renew_as_receiver(server: &TestServer, url: &str, created: &PinResponse) -> axum_test::TestRequest {
+        poll(server, &format!("{}/renew", url), created)
+    }
+
+    /// Where a party holding the pin's receiver secret takes the receiver seat of a rendezvous session.
+    fn rendezvous_url(namespace: &str, created: &PinResponse) -> String {
+        format!("/pin/{}/{}/ws?secret={}", namespace, created.pin, created.receiver_secret.as_deref().unwrap())
+    }
+
+    /// Where a party with just the pin takes the sender seat.
+    fn sender_url(namespace: &str, created: &PinResponse) -> String {
+        format!("/pin/{}/{}/ws", namespace, created.pin)
+    }
+
     #[tokio::test]
     async fn test_pin_item_creation() {
         let pin = "TEST".to_string();
-        let result = Some(HashMap::new());
-        let item = PinItem::new(pin.clone(), result.clone());
+        let result = Some(Keyring::generate().seal("test", &pin, &Plaintext::default()).unwrap());
+        let item = PinItem::new(pin.clone(), result.clone(), Utc::now());
         
         assert_eq!(item.pin, pin);
         assert_eq!(item.result, result);
         assert!(item.timestamp <= Utc::now());
     }
 
-    #[tokio::test]
-    async fn test_pin_item_hash() {
-        let pin1 = PinItem::new("TEST".to_string(), None);
-        let pin2 = PinItem::new("TEST".to_string(), None);
-        
-        // Items with same pin and timestamp should not necessarily have same hash
-        // due to timestamp precision differences
-        assert_eq!(pin1.pin, pin2.pin);
-    }
-
     #[tokio::test]
     async fn test_create_unique_pin() {
         let state = create_test_state();
         let namespace = "test";
         
-        let pin1 = create_unique_pin(namespace, &state);
+        let pin1 = create_unique_pin(namespace, None, &state).unwrap();
         assert!(pin1.is_some());
         
-        let pin1_val = pin1.unwrap();
-        assert_eq!(pin1_val.len(), PIN_LENGTH);
+        let pin1_val = pin1.unwrap().item;
+        assert_eq!(pin1_val.pin.len(), PIN_LENGTH);
         
         // Second pin should be different
-        let pin2 = create_unique_pin(namespace, &state);
+        let pin2 = create_unique_pin(namespace, None, &state).unwrap();
         assert!(pin2.is_some());
-        let pin2_val = pin2.unwrap();
+        let pin2_val = pin2.unwrap().item;
         assert_ne!(pin1_val, pin2_val);
     }
 
+    #[tokio::test]
+    async fn test_pins_are_scheduled_to_expire() {
+        let state = create_test_state();
+        let pin = create_unique_pin("test", None, &state).unwrap().unwrap().item.pin;
+        // Once when it expires, and again when its tombstone goes.
+        assert_eq!(state.expiry.pending(), 2);
+
+        // Answering a pin pushes its deadline back, so it is scheduled again.
+        let answered = update_pin_if_exists("test", &pin, Plaintext::default(), &state).unwrap();
+        assert!(matches!(answered, Change::Applied(_)));
+        assert_eq!(state.expiry.pending(), 4);
+        let missing = update_pin_if_exists("test", "NOPE", Plaintext::default(), &state).unwrap();
+        assert!(matches!(missing, Change::NotFound));
+        assert_eq!(state.expiry.pending(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unanswered_pin_expires_after_stale_age() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        tokio::spawn(state.expiry.clone().run());
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+
+        let pin = server.post("/pin/expiry").await.json::<PinResponse>().pin;
+        let almost = chrono::Duration::seconds(DEFAULT_TTL_SECS.into()) - chrono::Duration::seconds(1);
+        clock.advance(almost);
+        tokio::time::sleep(almost.to_std().unwrap()).await;
+        assert!(state.store.get("expiry", &pin).unwrap().is_some());
+
+        clock.advance(chrono::Duration::seconds(2));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let expired = state.store.get("expiry", &pin).unwrap().unwrap();
+        assert_eq!(expired.state(clock.now()), PinState::Expired);
+        let response = server.put(&format!("/pin/expiry/{}", pin)).json(&json!({"late": true})).await;
+        assert_eq!(response.status_code(), 410);
+        assert_eq!(response.text(), "Pin has expired.");
+
+        // The tombstone goes too, once it has had its own lifetime.
+        let tombstone = Duration::from_secs(state.config.ttl.tombstone_secs.into());
+        clock.advance(chrono::Duration::from_std(tombstone).unwrap());
+        tokio::time::sleep(tombstone).await;
+        assert!(state.store.get("expiry", &pin).unwrap().is_none());
+        let response = server.put(&format!("/pin/expiry/{}", pin)).json(&json!({"late": true})).await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_results_are_sealed_until_they_expire() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        tokio::spawn(state.expiry.clone().run());
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+
+        let pin = server.post("/pin/wifi").await.json::<PinResponse>().pin;
+        server.put(&format!("/pin/wifi/{}", pin)).json(&json!({"password": "hunter2"})).await;
+        let stored = state.store.get("wifi", &pin).unwrap().unwrap();
+        assert!(!serde_json::to_string(&stored.result).unwrap().contains("hunter2"));
+        assert_eq!(open_result("wifi", &stored, &state.keyring).unwrap().unwrap()["password"], json!("hunter2"));
+
+        // Nobody collected it, so it goes when the pin expires rather than with the tombstone.
+        let ttl = Duration::from_secs(DEFAULT_TTL_SECS.into()) + Duration::from_secs(1);
+        clock.advance(chrono::Duration::from_std(ttl).unwrap());
+        tokio::time::sleep(ttl).await;
+        let expired = state.store.get("wifi", &pin).unwrap().unwrap();
+        assert_eq!(expired.state(clock.now()), PinState::Expired);
+        assert!(expired.result.is_none());
+    }
+
     #[tokio::test]
     async fn test_create_new_pin_response() {
         let state = create_test_state();
         let namespace = "test";
         
-        let response = create_new_pin_response(namespace, &state);
+        let response = create_new_pin_response(namespace, None, &state).unwrap();
         assert!(response.is_some());
         
         let response = response.unwrap();
@@ -307,76 +1679,84 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_get_and_remove_pin_empty() {
+    async fn test_take_pin_empty() {
         let state = create_test_state();
         let namespace = "test";
         let pin = "ABCD";
         
         // Pin doesn't exist
-        let result = get_and_remove_pin_if_populated(namespace, pin, &state);
+        let result = take_pin_if_populated(namespace, pin, Utc::now(), &state).unwrap();
         assert!(result.is_none());
     }
 
     #[tokio::test]
-    async fn test_get_and_remove_pin_with_data() {
+    async fn test_take_pin_with_data() {
         let state = create_test_state();
         let namespace = "test";
         let pin = "ABCD";
-        let key = create_key(namespace, pin);
         
         // Insert pin with data
         let mut data = HashMap::new();
         data.insert("test".to_string(), json!("value"));
         
-        {
-            let mut write_handle = state.write.lock().unwrap();
-            write_handle.insert(key.clone(), PinItem::new(pin.to_string(), Some(data.clone())));
-            write_handle.refresh();
-        }
+        let data = Plaintext::from(data);
+        let sealed = state.keyring.seal(namespace, pin, &data).unwrap();
+        state.store.create_if_absent(namespace, PinItem::new(pin.to_string(), Some(sealed), Utc::now())).unwrap();
         
-        // Retrieve and remove
-        let result = get_and_remove_pin_if_populated(namespace, pin, &state);
+        // Retrieve and consume
+        let result = take_pin_if_populated(namespace, pin, Utc::now(), &state).unwrap();
         assert!(result.is_some());
         
         let response = result.unwrap();
-        assert_eq!(response.pin, pin);
+        assert_eq!(response.item.pin, pin);
         assert_eq!(response.result, Some(data));
         
-        // Should be removed now
-        assert!(!state.read.contains_key(&key));
+        // Only a tombstone without the data is left
+        let left = state.store.get(namespace, pin).unwrap().unwrap();
+        assert_eq!(left.state(Utc::now()), PinState::Consumed);
+        assert!(left.result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_results_that_cannot_be_opened_are_not_consumed() {
+        let state = create_test_state();
+        let data = Plaintext::from(HashMap::from([("test".to_string(), json!("value"))]));
+        let sealed = Keyring::generate().seal("test", "ABCD", &data).unwrap();
+        state.store.create_if_absent("test", PinItem::new("ABCD".to_string(), Some(sealed), Utc::now())).unwrap();
+
+        assert!(take_pin_if_populated("test", "ABCD", Utc::now(), &state).is_err());
+        let left = state.store.get("test", "ABCD").unwrap().unwrap();
+        assert_eq!(left.state(Utc::now()), PinState::Fulfilled);
+        assert!(left.result.is_some());
     }
 
     #[tokio::test]
-    async fn test_get_and_remove_pin_without_data() {
+    async fn test_take_pin_without_data() {
         let state = create_test_state();
         let namespace = "test";
         let pin = "ABCD";
-        let key = create_key(namespace, pin);
         
         // Insert pin without data
-        {
-            let mut write_handle = state.write.lock().unwrap();
-            write_handle.insert(key.clone(), PinItem::new(pin.to_string(), None));
-            write_handle.refresh();
-        }
+        state.store.create_if_absent(namespace, PinItem::new(pin.to_string(), None, Utc::now())).unwrap();
         
-        // Retrieve but don't remove (no data)
-        let result = get_and_remove_pin_if_populated(namespace, pin, &state);
+        // Retrieve but don't consume (no data)
+        let result = take_pin_if_populated(namespace, pin, Utc::now(), &state).unwrap();
         assert!(result.is_some());
         
         let response = result.unwrap();
-        assert_eq!(response.pin, pin);
+        assert_eq!(response.item.pin, pin);
         assert!(response.result.is_none());
         
-        // Should still exist
-        assert!(state.read.contains_key(&key));
+        // Should still be waiting
+        let left = state.store.get(namespace, pin).unwrap().unwrap();
+        assert_eq!(left.state(Utc::now()), PinState::AwaitingData);
     }
 
     // Integration tests for HTTP endpoints
     #[tokio::test]
     async fn test_health_endpoint() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         let response = server.get("/health").await;
@@ -388,7 +1768,7 @@ mod tests {
     #[tokio::test]
     async fn test_get_pin_endpoint() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         let response = server.post("/pin/testns").await;
@@ -402,7 +1782,7 @@ mod tests {
     #[tokio::test]
     async fn test_poll_pin_nonexistent() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         let response = server.post("/pin/testns/FAKE").await;
@@ -417,7 +1797,7 @@ mod tests {
     #[tokio::test]
     async fn test_respond_to_pin_nonexistent() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         let test_data = json!({"message": "test"});
@@ -431,7 +1811,7 @@ mod tests {
     #[tokio::test]
     async fn test_full_pin_workflow() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         // Step 1: Create a new pin
@@ -439,7 +1819,7 @@ mod tests {
         assert_eq!(response.status_code(), 200);
         
         let pin_response: PinResponse = response.json();
-        let pin = pin_response.pin;
+        let pin = pin_response.pin.clone();
         assert!(pin_response.result.is_none());
         
         // Step 2: Submit data to the pin
@@ -454,7 +1834,7 @@ mod tests {
         assert_eq!(response.text(), "Thanks!");
         
         // Step 3: Poll the pin to get the data
-        let response = server.post(&format!("/pin/workflow/{}", pin)).await;
+        let response = poll(&server, &format!("/pin/workflow/{}", pin), &pin_response).await;
         assert_eq!(response.status_code(), 200);
         
         let poll_response: PinResponse = response.json();
@@ -475,10 +1855,1149 @@ mod tests {
         assert!(new_poll_response.result.is_none());
     }
 
+    #[tokio::test]
+    async fn test_requested_ttl() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let mut state = create_test_state_with_clock(clock.clone());
+        let mut config = Config::default();
+        config.ttl.namespace_max_secs = "tv=120".parse().unwrap();
+        state.config = Arc::new(config);
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let pin: PinResponse = server.post("/pin/onboarding?ttl=3600").await.json();
+        assert_eq!(pin.created_at, clock.now());
+        assert_eq!(pin.server_time, clock.now());
+        assert_eq!(pin.expires_at - pin.created_at, chrono::Duration::hours(1));
+
+        let pin: PinResponse = server.post("/pin/onboarding").await.json();
+        assert_eq!(pin.expires_at - pin.created_at, chrono::Duration::minutes(10));
+
+        // Clamped to the server maximum, then to the namespace's own.
+        let pin: PinResponse = server.post("/pin/onboarding?ttl=86400").await.json();
+        assert_eq!(pin.expires_at - pin.created_at, chrono::Duration::hours(1));
+        let pin: PinResponse = server.post("/pin/tv?ttl=3600").await.json();
+        assert_eq!(pin.expires_at - pin.created_at, chrono::Duration::minutes(2));
+
+        let response = server.post("/pin/tv?ttl=soon").await;
+        assert_eq!(response.status_code(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_poll_reports_deadline() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns?ttl=120").await.json();
+        clock.advance(chrono::Duration::seconds(30));
+        server.put(&format!("/pin/ns/{}", created.pin)).json(&json!({"ok": true})).await;
+
+        // Answering restarts the pin's own lifetime.
+        let polled: PinResponse = poll(&server, &format!("/pin/ns/{}", created.pin), &created).await.json();
+        assert_eq!(polled.created_at, created.created_at);
+        assert_eq!(polled.server_time, clock.now());
+        assert_eq!(polled.expires_at, clock.now() + chrono::Duration::seconds(120));
+    }
+
+    #[tokio::test]
+    async fn test_renew_extends_deadline() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns?ttl=600").await.json();
+        assert_eq!(created.renewals, 0);
+        clock.advance(chrono::Duration::minutes(8));
+
+        let url = format!("/pin/ns/{}", created.pin);
+        assert_eq!(server.post(&format!("{}/renew", url)).await.status_code(), 401);
+        let response = renew_as_receiver(&server, &url, &created).await;
+        assert_eq!(response.status_code(), 200);
+        let renewed: PinResponse = response.json();
+        assert_eq!(renewed.pin, created.pin);
+        assert_eq!(renewed.renewals, 1);
+        assert_eq!(renewed.created_at, created.created_at);
+        assert_eq!(renewed.expires_at, clock.now() + chrono::Duration::minutes(10));
+        assert_eq!(state.expiry.pending(), 4);
+
+        let stored = state.store.get("ns", &created.pin).unwrap().unwrap();
+        assert_eq!(stored.expires_at, renewed.expires_at);
+        assert_eq!(stored.renewals, 1);
+    }
+
+    #[tokio::test]
+    async fn test_renew_stops_at_max_lifetime() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let mut state = create_test_state_with_clock(clock.clone());
+        let mut config = Config::default();
+        config.ttl.max_lifetime_secs = 900;
+        state.config = Arc::new(config);
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns?ttl=600").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+        clock.advance(chrono::Duration::minutes(9));
+
+        // Capped at fifteen minutes after creation rather than ten from now.
+        let renewed: PinResponse = renew_as_receiver(&server, &url, &created).await.json();
+        assert_eq!(renewed.expires_at, created.created_at + chrono::Duration::minutes(15));
+
+        clock.advance(chrono::Duration::minutes(1));
+        let response = renew_as_receiver(&server, &url, &created).await;
+        assert_eq!(response.status_code(), 409);
+        assert_eq!(response.text(), "Pin has reached its maximum lifetime.");
+
+        let response = server.post("/pin/ns/FAKE/renew").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_answering_stops_at_max_lifetime() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let mut state = create_test_state_with_clock(clock.clone());
+        let mut config = Config::default();
+        config.ttl.max_lifetime_secs = 900;
+        state.config = Arc::new(config);
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns?ttl=600").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+        clock.advance(chrono::Duration::minutes(9));
+
+        // Answering restarts the lifetime, but only up to fifteen minutes after creation.
+        assert_eq!(server.put(&url).json(&json!({"ok": true})).await.status_code(), 202);
+        assert_eq!(server.put(&url).json(&json!({"ok": false})).await.status_code(), 202);
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.expires_at, created.created_at + chrono::Duration::minutes(15));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_renewed_pin_outlives_its_ttl() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        tokio::spawn(state.expiry.clone().run());
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns?ttl=60").await.json();
+        let pin = created.pin.clone();
+        clock.advance(chrono::Duration::seconds(45));
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        renew_as_receiver(&server, &format!("/pin/ns/{}", pin), &created).await;
+
+        clock.advance(chrono::Duration::seconds(30));
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        let renewed = state.store.get("ns", &pin).unwrap().unwrap();
+        assert!(renewed.is_live(clock.now()));
+
+        clock.advance(chrono::Duration::seconds(31));
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        let expired = state.store.get("ns", &pin).unwrap().unwrap();
+        assert_eq!(expired.state(clock.now()), PinState::Expired);
+    }
+
+    #[tokio::test]
+    async fn test_creator_revokes_pin() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+
+        let created: PinResponse = server.post("/pin/pairing").await.json();
+        let creator_token = created.creator_token.unwrap();
+        let url = format!("/pin/pairing/{}", created.pin);
+        server.put(&url).json(&json!({"wifi": "secret"})).await;
+
+        let response = server.delete(&url).await;
+        assert_eq!(response.status_code(), 401);
+        let response = server.delete(&url).add_header(CREATOR_TOKEN_HEADER, "guess").await;
+        assert_eq!(response.status_code(), 403);
+        assert!(state.store.get("pairing", &created.pin).unwrap().unwrap().result.is_some());
+
+        let response = server.delete(&url).add_header(CREATOR_TOKEN_HEADER, &creator_token).await;
+        assert_eq!(response.status_code(), 204);
+        let stored = state.store.get("pairing", &created.pin).unwrap().unwrap();
+        assert!(stored.is_revoked());
+        assert!(stored.result.is_none());
+
+        // Senders and receivers both learn the pin was cancelled, not lost.
+        let response = server.put(&url).json(&json!({"late": true})).await;
+        assert_eq!(response.status_code(), 410);
+        assert_eq!(response.text(), "Pin has been revoked.");
+        assert_eq!(server.post(&url).await.status_code(), 410);
+        assert_eq!(server.post(&format!("{}/renew", url)).await.status_code(), 410);
+        let response = server.delete(&url).add_header(CREATOR_TOKEN_HEADER, &creator_token).await;
+        assert_eq!(response.status_code(), 410);
+    }
+
+    #[tokio::test]
+    async fn test_creator_token_is_only_issued_once() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        assert!(created.creator_token.is_some());
+        assert!(created.receiver_secret.is_some());
+        clock.advance(chrono::Duration::minutes(1));
+        let url = format!("/pin/ns/{}", created.pin);
+        let renewed: PinResponse = renew_as_receiver(&server, &url, &created).await.json();
+        assert!(renewed.creator_token.is_none());
+        server.put(&url).json(&json!({"ok": true})).await;
+        let polled: PinResponse = poll(&server, &url, &created).await.json();
+        assert!(polled.creator_token.is_none());
+        assert!(polled.receiver_secret.is_none());
+
+        let response = server.delete("/pin/ns/FAKE").add_header(CREATOR_TOKEN_HEADER, "any").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_receiver_secret_guards_result() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+        let created: PinResponse = server.post("/pin/tv").await.json();
+        let url = format!("/pin/tv/{}", created.pin);
+
+        // The short pin is enough to answer, but not to collect the answer.
+        let response = server.put(&url).json(&json!({"token": "abc"})).await;
+        assert_eq!(response.status_code(), 202);
+        let response = server.post(&url).await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(response.text(), "Receiver secret required.");
+        let response = server.post(&url).add_header(RECEIVER_SECRET_HEADER, "guess").await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Invalid receiver secret.");
+        let response = server.get(&format!("{}/events?secret=guess", url)).await;
+        assert_eq!(response.status_code(), 403);
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.state, PinState::Fulfilled);
+        assert_eq!(status.reads, 0);
+
+        let polled: PinResponse = poll(&server, &url, &created).await.json();
+        assert_eq!(polled.result.unwrap().get("token").unwrap(), &json!("abc"));
+
+        // A pin nobody was given a receiver secret for is closed to everyone.
+        let secretless = PinItem::new("OLD1".to_string(), None, Utc::now());
+        state.store.create_if_absent("tv", secretless).unwrap();
+        let response = server.post("/pin/tv/OLD1").await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Pin has no receiver.");
+        let response = server.post("/pin/tv/OLD1").add_header(RECEIVER_SECRET_HEADER, "guess").await;
+        assert_eq!(response.status_code(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_status_follows_pin_through_its_life() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns?ttl=120").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.state, PinState::AwaitingData);
+        assert_eq!(status.created_at, created.created_at);
+        assert_eq!(status.expires_at, created.expires_at);
+        assert_eq!(status.ended_at, None);
+        assert_eq!(status.reads, 0);
+
+        poll(&server, &url, &created).await;
+        clock.advance(chrono::Duration::seconds(10));
+        server.put(&url).json(&json!({"secret": "hunter2"})).await;
+        let response = server.get(&url).await;
+        assert!(!response.text().contains("hunter2"));
+        let status: StatusResponse = response.json();
+        assert_eq!(status.state, PinState::Fulfilled);
+        assert_eq!(status.reads, 1);
+
+        clock.advance(chrono::Duration::seconds(5));
+        poll(&server, &url, &created).await;
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.state, PinState::Consumed);
+        assert_eq!(status.ended_at, Some(clock.now()));
+        assert_eq!(status.reads, 2);
+
+        // Looking does not count as a read.
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.reads, 2);
+
+        assert_eq!(server.get("/pin/ns/FAKE").await.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_status_of_expired_and_revoked_pins() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let expiring: PinResponse = server.post("/pin/ns?ttl=60").await.json();
+        let revoking: PinResponse = server.post("/pin/ns?ttl=600").await.json();
+        server
+            .delete(&format!("/pin/ns/{}", revoking.pin))
+            .add_header(CREATOR_TOKEN_HEADER, &revoking.creator_token.unwrap())
+            .await;
+        clock.advance(chrono::Duration::seconds(90));
+
+        let status: StatusResponse = server.get(&format!("/pin/ns/{}", expiring.pin)).await.json();
+        assert_eq!(status.state, PinState::Expired);
+        assert_eq!(status.ended_at, Some(expiring.expires_at));
+        let response = server.get(&format!("/pin/ns/{}", revoking.pin)).await;
+        assert!(response.text().contains(r#""state":"revoked""#));
+        let status: StatusResponse = response.json();
+        assert_eq!(status.ended_at, Some(revoking.created_at));
+    }
+
+    #[tokio::test]
+    async fn test_strict_poll_explains_missing_pins() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let consumed: PinResponse = server.post("/pin/ns").await.json();
+        let consumed_url = format!("/pin/ns/{}", consumed.pin);
+        server.put(&consumed_url).json(&json!({"ok": true})).await;
+        poll(&server, &consumed_url, &consumed).await;
+        let expired: PinResponse = server.post("/pin/ns?ttl=60").await.json();
+        let expired_url = format!("/pin/ns/{}", expired.pin);
+        clock.advance(chrono::Duration::seconds(61));
+
+        let response = server.post(&format!("{}?strict=true", consumed_url)).await;
+        assert_eq!(response.status_code(), 410);
+        assert_eq!(response.text(), "Pin has already been consumed.");
+        let response = server.post(&format!("{}?strict=true", expired_url)).await;
+        assert_eq!(response.status_code(), 410);
+        assert_eq!(response.text(), "Pin has expired.");
+        let response = server.post("/pin/ns/FAKE?strict=true").await;
+        assert_eq!(response.status_code(), 404);
+
+        // Senders get the same answers.
+        let response = server.put(&consumed_url).json(&json!({"again": true})).await;
+        assert_eq!(response.status_code(), 410);
+        assert_eq!(response.text(), "Pin has already been consumed.");
+        let response = server.put(&expired_url).json(&json!({"late": true})).await;
+        assert_eq!(response.status_code(), 410);
+
+        // Without strict, the receiver still gets a replacement.
+        let replacement: PinResponse = server.post(&expired_url).await.json();
+        assert_ne!(replacement.pin, expired.pin);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_poll_wakes_when_answered() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+
+        let started = tokio::time::Instant::now();
+        let poll = async {
+            let response = poll(&server, &format!("{}?wait=30", url), &created).await;
+            (response, started.elapsed())
+        };
+        let answer = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            server.put(&url).json(&json!({"ok": true})).await;
+        };
+        let ((response, waited), _) = tokio::join!(poll, answer);
+
+        let body: PinResponse = response.json();
+        assert_eq!(body.pin, created.pin);
+        assert_eq!(body.result.unwrap().get("ok").unwrap(), &json!(true));
+        assert_eq!(waited, Duration::from_secs(5));
+        assert!(state.waiters.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_poll_gives_up_after_wait() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+
+        let started = tokio::time::Instant::now();
+        let body: PinResponse = poll(&server, &format!("{}?wait=10", url), &created).await.json();
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert_eq!(body.pin, created.pin);
+        assert!(body.result.is_none());
+
+        // Capped at MAX_WAIT_SECS.
+        let started = tokio::time::Instant::now();
+        poll(&server, &format!("{}?wait=86400", url), &created).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(state.config.max_wait_secs.into()));
+        assert!(state.waiters.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_poll_looks_again_when_the_wait_ends() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+
+        let poll = poll(&server, &format!("{}?wait=10", url), &created);
+        // Answered without waking anyone, as another replica would.
+        let answer = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            let data = Plaintext::from(HashMap::from([("ok".to_string(), json!(true))]));
+            let sealed = state.keyring.seal("ns", &created.pin, &data).unwrap();
+            state
+                .store
+                .update("ns", &created.pin, &mut |item| {
+                    item.result = Some(sealed.clone());
+                    true
+                })
+                .unwrap();
+        };
+        let (response, _) = tokio::join!(poll, answer);
+
+        let body: PinResponse = response.json();
+        assert_eq!(body.result.unwrap().get("ok").unwrap(), &json!(true));
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.state, PinState::Consumed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_poll_hears_about_revocation() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state)).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+
+        let started = tokio::time::Instant::now();
+        let poll = async {
+            let response = poll(&server, &format!("{}?wait=30", url), &created).await;
+            (response, started.elapsed())
+        };
+        let revoke = async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            server
+                .delete(&url)
+                .add_header(CREATOR_TOKEN_HEADER, created.creator_token.as_deref().unwrap())
+                .await;
+        };
+        let ((response, waited), _) = tokio::join!(poll, revoke);
+        assert_eq!(response.status_code(), 410);
+        assert_eq!(waited, Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_ends_long_polls_and_event_streams() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+        let secret = created.receiver_secret.as_deref().unwrap();
+
+        let started = tokio::time::Instant::now();
+        let poll = async {
+            let response = poll(&server, &format!("{}?wait=30", url), &created).await;
+            (response, started.elapsed())
+        };
+        let events = async {
+            let response = server
+                .get(&format!("{}/events", url))
+                .add_header(RECEIVER_SECRET_HEADER, secret)
+                .await;
+            (response, started.elapsed())
+        };
+        let shutdown = async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            state.shutdown.cancel();
+        };
+        let ((polled, poll_waited), (streamed, stream_waited), _) = tokio::join!(poll, events, shutdown);
+
+        let body: PinResponse = polled.json();
+        assert_eq!(body.pin, created.pin);
+        assert!(body.result.is_none());
+        assert_eq!(poll_waited, Duration::from_secs(2));
+        assert!(parse_events(&streamed.text()).is_empty());
+        assert_eq!(stream_waited, Duration::from_secs(2));
+        assert!(state.waiters.is_empty());
+    }
+
+    /// Splits an event stream body into its event names and data.
+    fn parse_events(body: &str) -> Vec<(String, Value)> {
+        body.split("\n\n")
+            .filter(|block| !block.trim().is_empty())
+            .map(|block| {
+                let field = |name: &str| {
+                    block
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                (field("event: "), serde_json::from_str(&field("data: ")).unwrap())
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_events_follow_pin_until_fulfilled() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state.clone())).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+
+        let secret = created.receiver_secret.as_deref().unwrap();
+        let events = async {
+            server
+                .get(&format!("{}/events", url))
+                .add_header(RECEIVER_SECRET_HEADER, secret)
+                .await
+        };
+        let answer = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            renew_as_receiver(&server, &url, &created).await;
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            server.put(&url).json(&json!({"ssid": "home"})).await;
+        };
+        let (response, _) = tokio::join!(events, answer);
+        assert_eq!(response.header("content-type"), "text/event-stream");
+        let events = parse_events(&response.text());
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["renewed", "heartbeat", "fulfilled"]);
+        assert_eq!(events[0].1["renewals"], 1);
+        assert_eq!(events[2].1["result"], json!({"ssid": "home"}));
+
+        // Delivering the result over the stream used it up.
+        let status: StatusResponse = server.get(&url).await.json();
+        assert_eq!(status.state, PinState::Consumed);
+        assert!(state.waiters.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_events_report_expiry() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        tokio::spawn(state.expiry.clone().run());
+        let server = TestServer::new(create_router(state)).unwrap();
+        let created: PinResponse = server.post("/pin/ns?ttl=60").await.json();
+        let url = format!(
+            "/pin/ns/{}/events?secret={}",
+            created.pin,
+            created.receiver_secret.unwrap()
+        );
+
+        let events = async { server.get(&url).await };
+        let expire = async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            clock.advance(chrono::Duration::seconds(61));
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        };
+        let (response, _) = tokio::join!(events, expire);
+        let events = parse_events(&response.text());
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["heartbeat", "heartbeat", "heartbeat", "expired"]);
+        assert_eq!(events[3].1["state"], "expired");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_events_report_revocation() {
+        let state = create_test_state();
+        let server = TestServer::new(create_router(state)).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        let url = format!("/pin/ns/{}", created.pin);
+
+        let events_url = format!("{}/events?secret={}", url, created.receiver_secret.as_deref().unwrap());
+        let events = async { server.get(&events_url).await };
+        let revoke = async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            server
+                .delete(&url)
+                .add_header(CREATOR_TOKEN_HEADER, created.creator_token.as_deref().unwrap())
+                .await;
+        };
+        let (response, _) = tokio::join!(events, revoke);
+        let events = parse_events(&response.text());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "revoked");
+        assert_eq!(events[0].1["state"], "revoked");
+
+        // There is nothing left to follow.
+        let response = server.get(&format!("{}/events", url)).await;
+        assert_eq!(response.status_code(), 410);
+        let response = server.get("/pin/ns/NONE/events").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    fn device_flow_server(clock: Arc<ManualClock>) -> TestServer {
+        let mut state = create_test_state_with_clock(clock);
+        state.config = Arc::new(Config {
+            device_verification_uri: Some("https://example.com/activate".to_string()),
+            ..Config::default()
+        });
+        TestServer::new(create_router(state)).unwrap()
+    }
+
+    async fn request_token(server: &TestServer, device_code: &str) -> axum_test::TestResponse {
+        server
+            .post("/device/tv/token")
+            .form(&[("grant_type", DEVICE_CODE_GRANT_TYPE), ("device_code", device_code)])
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_issues_codes() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let server = device_flow_server(clock);
+
+        let response = server.post("/device/tv/authorize").form(&[("client_id", "tv-app")]).await;
+        assert_eq!(response.status_code(), 200);
+        let authorization: DeviceAuthorizationResponse = response.json();
+        assert_eq!(authorization.user_code.len(), PIN_LENGTH);
+        assert!(authorization.device_code.starts_with(&format!("{}.", authorization.user_code)));
+        assert_eq!(authorization.verification_uri, "https://example.com/activate");
+        assert_eq!(
+            authorization.verification_uri_complete,
+            format!("https://example.com/activate?user_code={}", authorization.user_code)
+        );
+        assert_eq!(authorization.expires_in, i64::from(DEFAULT_TTL_SECS));
+        assert_eq!(authorization.interval, 5);
+
+        // Off unless a verification URI is configured.
+        let server = TestServer::new(create_router(create_test_state())).unwrap();
+        assert_eq!(server.post("/device/tv/authorize").await.status_code(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_hands_over_approved_token() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let server = device_flow_server(clock.clone());
+        let authorization: DeviceAuthorizationResponse = server.post("/device/tv/authorize").await.json();
+
+        let response = request_token(&server, &authorization.device_code).await;
+        assert_eq!(response.status_code(), 400);
+        assert_eq!(response.header("cache-control"), "no-store");
+        assert_eq!(response.json::<OAuthError>().error, "authorization_pending");
+        let response = request_token(&server, &authorization.device_code).await;
+        assert_eq!(response.json::<OAuthError>().error, "slow_down");
+
+        // The verification page approves the user code with the token response.
+        let token = json!({"access_token": "at-123", "token_type": "Bearer", "expires_in": 3600});
+        let response = server.put(&format!("/pin/tv/{}", authorization.user_code)).json(&token).await;
+        assert_eq!(response.status_code(), 202);
+        // Each slow_down adds five seconds, so the original interval is no longer enough.
+        clock.advance(chrono::Duration::seconds(5));
+        let response = request_token(&server, &authorization.device_code).await;
+        assert_eq!(response.json::<OAuthError>().error, "slow_down");
+        clock.advance(chrono::Duration::seconds(10));
+        let response = request_token(&server, &authorization.device_code).await;
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header("cache-control"), "no-store");
+        assert_eq!(response.json::<Value>(), token);
+
+        let response = request_token(&server, &authorization.device_code).await;
+        assert_eq!(response.json::<OAuthError>().error, "invalid_grant");
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_errors() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let server = device_flow_server(clock.clone());
+
+        let denied: DeviceAuthorizationResponse = server.post("/device/tv/authorize").await.json();
+        server
+            .put(&format!("/pin/tv/{}", denied.user_code))
+            .json(&json!({"error": "access_denied"}))
+            .await;
+        let response = request_token(&server, &denied.device_code).await;
+        assert_eq!(response.json::<OAuthError>().error, "access_denied");
+
+        let expired: DeviceAuthorizationResponse = server.post("/device/tv/authorize").await.json();
+        clock.advance(chrono::Duration::seconds(expired.expires_in + 1));
+        let response = request_token(&server, &expired.device_code).await;
+        assert_eq!(response.json::<OAuthError>().error, "expired_token");
+
+        // Knowing the user code is not enough to collect the token.
+        let guessed = format!("{}.guess", expired.user_code);
+        let response = request_token(&server, &guessed).await;
+        assert_eq!(response.json::<OAuthError>().error, "invalid_grant");
+        let response = server
+            .post("/device/tv/token")
+            .form(&[("grant_type", "password"), ("device_code", expired.device_code.as_str())])
+            .await;
+        assert_eq!(response.json::<OAuthError>().error, "unsupported_grant_type");
+        let response = server.post("/device/tv/token").text("device_code").await;
+        assert_eq!(response.json::<OAuthError>().error, "invalid_request");
+    }
+
+    fn http_server(state: BiboopState) -> TestServer {
+        // WebSockets need a real connection to upgrade.
+        TestServer::builder()
+            .http_transport()
+            .build(create_router(state))
+            .unwrap()
+    }
+
+    /// Reads the close frame a session ends with, as its code and reason.
+    async fn receive_close(socket: &mut axum_test::TestWebSocket) -> (u16, String) {
+        match socket.receive_message().await {
+            axum_test::WsMessage::Close(Some(frame)) => (frame.code.into(), frame.reason.to_string()),
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_relays_between_parties() {
+        let state = create_test_state();
+        let server = http_server(state.clone());
+        let created: PinResponse = server.post("/pin/pairing").await.json();
+        let url = rendezvous_url("pairing", &created);
+
+        // The short pin is enough to send, but a wrong secret never gets the receiver seat.
+        let response = server.get_websocket(&format!("/pin/pairing/{}/ws?secret=guess", created.pin)).await;
+        assert_eq!(response.status_code(), 403);
+
+        let mut device_a = server.get_websocket(&url).await.into_websocket().await;
+        // Sent before the other side has joined.
+        device_a.send_json(&json!({"offer": "sdp-a"})).await;
+        let mut device_b = server.get_websocket(&sender_url("pairing", &created)).await.into_websocket().await;
+        device_b.assert_receive_json(&json!({"offer": "sdp-a"})).await;
+        device_b.send_json(&json!({"answer": "sdp-b"})).await;
+        device_a.assert_receive_json(&json!({"answer": "sdp-b"})).await;
+        device_a.send_json(&json!({"ack": true})).await;
+        device_b.assert_receive_json(&json!({"ack": true})).await;
+
+        let response = server.get_websocket(&url).await;
+        assert_eq!(response.status_code(), 409);
+        assert_eq!(response.text(), "The receiver has already joined.");
+        let response = server.get_websocket(&sender_url("pairing", &created)).await;
+        assert_eq!(response.status_code(), 409);
+        assert_eq!(response.text(), "The sender has already joined.");
+
+        device_a.close().await;
+        let (code, reason) = receive_close(&mut device_b).await;
+        assert_eq!(code, close_code::NORMAL);
+        assert_eq!(reason, "The other party has left.");
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_only_relays_small_json() {
+        let state = create_test_state();
+        let server = http_server(state);
+        let created: PinResponse = server.post("/pin/pairing").await.json();
+        let url = rendezvous_url("pairing", &created);
+
+        let mut socket = server.get_websocket(&url).await.into_websocket().await;
+        socket.send_json(&json!({"data": "x".repeat(4000)})).await;
+        assert_eq!(receive_close(&mut socket).await.0, close_code::SIZE);
+
+        let mut socket = server.get_websocket(&url).await.into_websocket().await;
+        socket.send_text("not json").await;
+        let (code, reason) = receive_close(&mut socket).await;
+        assert_eq!(code, close_code::UNSUPPORTED);
+        assert_eq!(reason, "Messages must be JSON.");
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_ends_with_pin() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let state = create_test_state_with_clock(clock.clone());
+        let server = http_server(state.clone());
+
+        let created: PinResponse = server.post("/pin/pairing").await.json();
+        let url = format!("/pin/pairing/{}", created.pin);
+        let ws_url = rendezvous_url("pairing", &created);
+        let mut device_a = server.get_websocket(&ws_url).await.into_websocket().await;
+        let mut device_b = server.get_websocket(&sender_url("pairing", &created)).await.into_websocket().await;
+        server
+            .delete(&url)
+            .add_header(CREATOR_TOKEN_HEADER, created.creator_token.as_deref().unwrap())
+            .await;
+        assert_eq!(receive_close(&mut device_a).await.1, "Pin has been revoked.");
+        assert_eq!(receive_close(&mut device_b).await.1, "Pin has been revoked.");
+
+        let created: PinResponse = server.post("/pin/pairing").await.json();
+        let pin = created.pin.clone();
+        let url = rendezvous_url("pairing", &created);
+        let mut socket = server.get_websocket(&url).await.into_websocket().await;
+        clock.advance(chrono::Duration::seconds(DEFAULT_TTL_SECS.into()));
+        // As the expiry task does when the deadline passes.
+        state.waiters.notify("pairing", &pin);
+        assert_eq!(receive_close(&mut socket).await.1, "Pin has expired.");
+        let response = server.get_websocket(&url).await;
+        assert_eq!(response.status_code(), 410);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let mut state = create_test_state_with_clock(clock.clone());
+        state.rate_limiter = Arc::new(RateLimiter::new(
+            RateLimits {
+                create: "2/60".parse().unwrap(),
+                poll: "off".parse().unwrap(),
+                submit: "1/60".parse().unwrap(),
+            },
+            RateLimits::OFF,
+        ));
+        let server = TestServer::new(create_router(state)).unwrap();
+
+        let created: PinResponse = server.post("/pin/ns").await.json();
+        server.post("/pin/ns").await;
+        let response = server.post("/pin/ns").await;
+        assert_eq!(response.status_code(), 429);
+        assert_eq!(response.header("retry-after"), "30");
+        assert_eq!(response.text(), "Too many requests.");
+        // The budget covers every namespace, but each kind of request has its own.
+        assert_eq!(server.post("/pin/other").await.status_code(), 429);
+        let url = format!("/pin/ns/{}", created.pin);
+        assert_eq!(server.put(&url).json(&json!({"ok": true})).await.status_code(), 202);
+        assert_eq!(server.put(&url).json(&json!({"ok": true})).await.status_code(), 429);
+        for _ in 0..10 {
+            assert_eq!(server.get(&url).await.status_code(), 200);
+        }
+
+        clock.advance(chrono::Duration::seconds(30));
+        assert_eq!(server.post("/pin/ns").await.status_code(), 200);
+        assert_eq!(server.post("/pin/ns").await.status_code(), 429);
+
+        // Revoking draws on the create budget, which is spent.
+        let response = server
+            .delete(&url)
+            .add_header(CREATOR_TOKEN_HEADER, created.creator_token.as_deref().unwrap())
+            .await;
+        assert_eq!(response.status_code(), 429);
+        clock.advance(chrono::Duration::seconds(30));
+        let response = server
+            .delete(&url)
+            .add_header(CREATOR_TOKEN_HEADER, created.creator_token.as_deref().unwrap())
+            .await;
+        assert_eq!(response.status_code(), 204);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies_say_who_the_client_is() {
+        let mut state = create_test_state();
+        state.rate_limiter = Arc::new(RateLimiter::new(
+            RateLimits {
+                create: "1/60".parse().unwrap(),
+                ..RateLimits::OFF
+            },
+            RateLimits {
+                create: "3/60".parse().unwrap(),
+                ..RateLimits::OFF
+            },
+        ));
+        state.config = Arc::new(Config {
+            trusted_proxies: "10.0.0.0/8".parse().unwrap(),
+            ..Config::default()
+        });
+        let behind = |addr: [u8; 4]| Peer { addr: (addr, 40000).into(), client_certified: false };
+        let proxied = TestServer::new(create_router(state.clone()).layer(MockConnectInfo(behind([10, 0, 0, 1])))).unwrap();
+        let direct = TestServer::new(create_router(state).layer(MockConnectInfo(behind([203, 0, 113, 9])))).unwrap();
+
+        // Each client behind the proxy has a budget of its own.
+        let create = |server: &TestServer, client: &str| server.post("/pin/ns").add_header(FORWARDED_FOR_HEADER, client.to_string());
+        assert_eq!(create(&proxied, "198.51.100.1").await.status_code(), 200);
+        assert_eq!(create(&proxied, "198.51.100.1").await.status_code(), 429);
+        assert_eq!(create(&proxied, "1.2.3.4, 198.51.100.2").await.status_code(), 200);
+        // Anyone else saying who they are is ignored.
+        assert_eq!(create(&direct, "198.51.100.3").await.status_code(), 200);
+        assert_eq!(create(&direct, "198.51.100.4").await.status_code(), 429);
+        // The namespace's budget is spent, whoever asks.
+        let response = create(&proxied, "198.51.100.5").await;
+        assert_eq!(response.status_code(), 429);
+        assert_eq!(response.header("retry-after"), "20");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_guessing_pins_gets_client_banned() {
+        let mut state = create_test_state();
+        state.lockout = Arc::new(Lockout::new(LockoutPolicy {
+            delay_after: 1,
+            ban_after: 3,
+            window_secs: 60,
+            ban_secs: 120,
+        }));
+        state.config = Arc::new(Config {
+            admin_token: Some("hunter2".to_string()),
+            ..Config::default()
+        });
+        let server = TestServer::new(create_router(state)).unwrap();
+        let created: PinResponse = server.post("/pin/ns").await.json();
+
+        assert_eq!(server.put("/pin/ns/AAAA").json(&json!({"ok": true})).await.status_code(), 404);
+        let started = tokio::time::Instant::now();
+        assert_eq!(server.post("/pin/ns/BBBB?strict=true").await.status_code(), 404);
+        assert_eq!(started.elapsed(), Duration::from_millis(250));
+        assert_eq!(server.put("/pin/ns/CCCC").json(&json!({"ok": true})).await.status_code(), 404);
+
+        let response = server.get(&format!("/pin/ns/{}", created.pin)).await;
+        assert_eq!(response.status_code(), 429);
+        assert_eq!(response.header("retry-after"), "120");
+        assert_eq!(response.text(), "Too many requests for pins that do not exist.");
+        // Only from that namespace.
+        assert_eq!(server.post("/pin/other").await.status_code(), 200);
+
+        assert_eq!(server.get("/admin/bans").await.status_code(), 401);
+        assert_eq!(server.get("/admin/bans").authorization_bearer("letmein").await.status_code(), 403);
+        let bans: Value = server.get("/admin/bans").authorization_bearer("hunter2").await.json();
+        assert_eq!(bans.as_array().unwrap().len(), 1);
+        assert_eq!(bans[0]["namespace"], "ns");
+        assert_eq!(bans[0]["client"], Value::Null);
+        assert_eq!(bans[0]["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_api_is_off_without_token() {
+        let server = TestServer::new(create_router(create_test_state())).unwrap();
+        let response = server.get("/admin/bans").authorization_bearer("anything").await;
+        assert_eq!(response.status_code(), 404);
+    }
+
+    fn pow_server() -> (BiboopState, TestServer) {
+        let mut state = create_test_state();
+        state.pow = Arc::new(ProofOfWork::new(PowPolicy {
+            min_difficulty: 4,
+            max_difficulty: 12,
+            challenge_secs: 60,
+        }));
+        (state.clone(), TestServer::new(create_router(state)).unwrap())
+    }
+
+    async fn solve_challenge(server: &TestServer, namespace: &str) -> String {
+        let challenge: ChallengeResponse = server.post(&format!("/challenge/{}", namespace)).await.json();
+        // The namespace is nearly empty, so the challenge is the easiest there is.
+        assert_eq!(challenge.difficulty, 4);
+        assert_eq!(challenge.expires_in, 60);
+        pow::solve(&challenge.challenge, challenge.difficulty)
+    }
+
+    #[tokio::test]
+    async fn test_pin_creation_requires_proof_of_work() {
+        let (state, server) = pow_server();
+        let response = server.post("/pin/ns").await;
+        assert_eq!(response.status_code(), 428);
+        assert_eq!(response.text(), "Proof of work required.");
+        assert_eq!(server.post("/pin/ns").add_header(PROOF_OF_WORK_HEADER, "nonsense").await.status_code(), 403);
+
+        let stamp = solve_challenge(&server, "ns").await;
+        // Challenges are bound to their namespace.
+        assert_eq!(server.post("/pin/other").add_header(PROOF_OF_WORK_HEADER, &stamp).await.status_code(), 403);
+        let created = server.post("/pin/ns").add_header(PROOF_OF_WORK_HEADER, &stamp).await;
+        assert_eq!(created.status_code(), 200);
+        let created: PinResponse = created.json();
+        assert_eq!(server.post("/pin/ns").add_header(PROOF_OF_WORK_HEADER, &stamp).await.status_code(), 403);
+        assert!(state.pow.is_empty());
+
+        // Polling a pin that is still live needs no proof, but replacing one does.
+        assert_eq!(poll(&server, &format!("/pin/ns/{}", created.pin), &created).await.status_code(), 200);
+        assert_eq!(server.post("/pin/ns/ZZZZ").await.status_code(), 428);
+        let stamp = solve_challenge(&server, "ns").await;
+        let replaced = server.post("/pin/ns/ZZZZ").add_header(PROOF_OF_WORK_HEADER, &stamp).await;
+        assert_eq!(replaced.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_challenges_are_off_by_default() {
+        let server = TestServer::new(create_router(create_test_state())).unwrap();
+        assert_eq!(server.post("/challenge/ns").await.status_code(), 404);
+    }
+
+    fn api_key_server() -> TestServer {
+        let mut state = create_test_state();
+        let keys = format!(
+            r#"[
+                {{"id": "app", "secret_sha256": "{}", "scopes": ["create", "poll"], "namespaces": ["app-*"]}},
+                {{"id": "sender", "secret_sha256": "{}", "scopes": ["submit"], "namespaces": ["*"]}},
+                {{"id": "ops", "secret_sha256": "{}", "scopes": ["admin"], "namespaces": ["app-*"]}}
+            ]"#,
+            token::hash("app-key"),
+            token::hash("sender-key"),
+            token::hash("ops-key"),
+        );
+        state.api_keys = Some(Arc::new(ApiKeys::from_json(&keys).unwrap()));
+        state.lockout = Arc::new(Lockout::new(LockoutPolicy {
+            delay_after: 0,
+            ban_after: 1,
+            window_secs: 60,
+            ban_secs: 60,
+        }));
+        // Key holders skip the challenge.
+        state.pow = Arc::new(ProofOfWork::new(PowPolicy {
+            min_difficulty: 20,
+            max_difficulty: 20,
+            challenge_secs: 60,
+        }));
+        TestServer::new(create_router(state)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_guard_every_route() {
+        let server = api_key_server();
+        let response = server.post("/pin/app-tv").await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(response.header("www-authenticate"), "Bearer");
+        assert_eq!(response.text(), "Credentials required.");
+        let response = server.post("/pin/app-tv").authorization_bearer("guess").await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(response.text(), "Invalid API key.");
+        let response = server.post("/pin/other").authorization_bearer("app-key").await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Credentials are not allowed in this namespace.");
+
+        let created = server.post("/pin/app-tv").authorization_bearer("app-key").await;
+        assert_eq!(created.status_code(), 200);
+        let created: PinResponse = created.json();
+        let url = format!("/pin/app-tv/{}", created.pin);
+        let response = server.put(&url).authorization_bearer("app-key").json(&json!({"ok": true})).await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Credentials are not allowed to do this.");
+        let response = server.put(&url).add_header(API_KEY_HEADER, "sender-key").json(&json!({"ok": true})).await;
+        assert_eq!(response.status_code(), 202);
+        let polled: PinResponse = poll(&server, &url, &created).authorization_bearer("app-key").await.json();
+        assert_eq!(polled.result.unwrap()["ok"], true);
+
+        // Revoking belongs to whoever issues pins, not to senders.
+        let created: PinResponse = server.post("/pin/app-tv").authorization_bearer("app-key").await.json();
+        let url = format!("/pin/app-tv/{}", created.pin);
+        let creator_token = created.creator_token.as_deref().unwrap();
+        let response = server
+            .delete(&url)
+            .add_header(API_KEY_HEADER, "sender-key")
+            .add_header(CREATOR_TOKEN_HEADER, creator_token)
+            .await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Credentials are not allowed to do this.");
+        let response = server
+            .delete(&url)
+            .authorization_bearer("app-key")
+            .add_header(CREATOR_TOKEN_HEADER, creator_token)
+            .await;
+        assert_eq!(response.status_code(), 204);
+
+        assert_eq!(server.get("/health").await.status_code(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_admin_keys_see_bans_in_their_namespaces() {
+        let server = api_key_server();
+        for namespace in ["app-tv", "elsewhere"] {
+            let url = format!("/pin/{}/AAAA", namespace);
+            server.put(&url).authorization_bearer("sender-key").json(&json!({"ok": true})).await;
+        }
+
+        assert_eq!(server.get("/admin/bans").await.status_code(), 401);
+        assert_eq!(server.get("/admin/bans").authorization_bearer("app-key").await.status_code(), 403);
+        let bans: Value = server.get("/admin/bans").authorization_bearer("ops-key").await.json();
+        assert_eq!(bans.as_array().unwrap().len(), 1);
+        assert_eq!(bans[0]["namespace"], "app-tv");
+    }
+
+    /// Signs claims with a freshly generated key, serving a server that trusts
+    /// it as well as the API keys of `api_key_server`.
+    fn jwt_server(clock: Arc<ManualClock>) -> (TestServer, impl Fn(Value) -> String) {
+        use base64::Engine;
+        use jsonwebtoken::{Algorithm, EncodingKey, Header};
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let public = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap().public_key().as_ref().to_vec();
+        let jwks = json!({"keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "kid": "test",
+            "x": base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(public),
+        }]});
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jwks.json");
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        let verifier = JwtVerifier::new(JwtSettings {
+            jwks: JwksSource::File(path),
+            issuer: "https://id.example.com/".to_string(),
+            audience: "configgymajiggy".to_string(),
+            namespaces_claim: "namespaces".to_string(),
+            scopes_claim: "scope".to_string(),
+        })
+        .unwrap();
+
+        let mut state = create_test_state_with_clock(clock.clone());
+        state.api_keys = Some(Arc::new(
+            ApiKeys::from_json(&format!(
+                r#"[{{"id": "sender", "secret_sha256": "{}", "scopes": ["submit"], "namespaces": ["*"]}}]"#,
+                token::hash("sender-key")
+            ))
+            .unwrap(),
+        ));
+        state.jwt = Some(Arc::new(verifier));
+        let key = EncodingKey::from_ed_der(pkcs8.as_ref());
+        let sign = move |mut claims: Value| {
+            let defaults = json!({
+                "iss": "https://id.example.com/",
+                "aud": "configgymajiggy",
+                "sub": "alice",
+                "exp": clock.now().timestamp() + 300,
+            });
+            for (claim, value) in defaults.as_object().unwrap() {
+                claims.as_object_mut().unwrap().entry(claim).or_insert(value.clone());
+            }
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some("test".to_string());
+            jsonwebtoken::encode(&header, &claims, &key).unwrap()
+        };
+        (TestServer::new(create_router(state)).unwrap(), sign)
+    }
+
+    #[tokio::test]
+    async fn test_jwts_are_accepted_alongside_api_keys() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let (server, sign) = jwt_server(clock.clone());
+        let app = sign(json!({"scope": "create poll", "namespaces": ["app-*"]}));
+
+        let response = server.post("/pin/other").authorization_bearer(&app).await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Credentials are not allowed in this namespace.");
+        let created = server.post("/pin/app-tv").authorization_bearer(&app).await;
+        assert_eq!(created.status_code(), 200);
+        let created: PinResponse = created.json();
+        let url = format!("/pin/app-tv/{}", created.pin);
+        let response = server.put(&url).authorization_bearer("sender-key").json(&json!({"ok": true})).await;
+        assert_eq!(response.status_code(), 202);
+
+        let forged = sign(json!({"scope": "poll", "namespaces": ["*"], "iss": "https://evil.example.com/"}));
+        let response = poll(&server, &url, &created).authorization_bearer(&forged).await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(response.header("www-authenticate"), r#"Bearer error="invalid_token""#);
+        assert_eq!(response.text(), "Invalid bearer token.");
+
+        clock.advance(chrono::Duration::seconds(3600));
+        let response = poll(&server, &url, &created).authorization_bearer(&app).await;
+        assert_eq!(response.status_code(), 401);
+        assert_eq!(response.text(), "Bearer token has expired.");
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_are_required_per_namespace() {
+        let mut state = create_test_state();
+        state.config = Arc::new(Config {
+            tls: Some(TlsSettings {
+                cert_path: "cert.pem".into(),
+                key_path: "key.pem".into(),
+                client_ca_path: Some("ca.pem".into()),
+                client_cert_namespaces: "secure-*".parse().unwrap(),
+            }),
+            ..Config::default()
+        });
+        let peer = |client_certified| Peer { addr: ([10, 0, 0, 1], 443).into(), client_certified };
+        let anonymous = TestServer::new(create_router(state.clone()).layer(MockConnectInfo(peer(false)))).unwrap();
+        let certified = TestServer::new(create_router(state).layer(MockConnectInfo(peer(true)))).unwrap();
+
+        let response = anonymous.post("/pin/secure-tv").await;
+        assert_eq!(response.status_code(), 403);
+        assert_eq!(response.text(), "Client certificate required.");
+        assert_eq!(anonymous.post("/pin/tv").await.status_code(), 200);
+        assert_eq!(anonymous.get("/health").await.status_code(), 200);
+
+        let created: PinResponse = certified.post("/pin/secure-tv").await.json();
+        let url = format!("/pin/secure-tv/{}", created.pin);
+        assert_eq!(anonymous.put(&url).json(&json!({"ok": true})).await.status_code(), 403);
+        assert_eq!(certified.put(&url).json(&json!({"ok": true})).await.status_code(), 202);
+    }
+
     #[tokio::test]
     async fn test_payload_too_large() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         // First create a pin
@@ -498,7 +3017,7 @@ mod tests {
     #[tokio::test]
     async fn test_namespace_isolation() {
         let state = create_test_state();
-        let app = create_router().with_state(state);
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         // Create pins in different namespaces
@@ -519,7 +3038,7 @@ mod tests {
         assert_eq!(response.status_code(), 404);
         
         // But we should be able to poll from the correct namespace
-        let response = server.post(&format!("/pin/ns1/{}", pin1.pin)).await;
+        let response = poll(&server, &format!("/pin/ns1/{}", pin1.pin), &pin1).await;
         assert_eq!(response.status_code(), 200);
         
         let poll_response: PinResponse = response.json();
@@ -537,7 +3056,7 @@ mod tests {
             let namespace = format!("concurrent_{}", i);
             let state_clone = state.clone();
             let handle = tokio::spawn(async move {
-                create_unique_pin(&namespace, &state_clone)
+                create_unique_pin(&namespace, None, &state_clone).unwrap().map(|new_pin| new_pin.item.pin)
             });
             handles.push(handle);
         }
@@ -559,8 +3078,10 @@ mod tests {
 
     #[tokio::test]
     async fn test_high_frequency_operations() {
-        let state = create_test_state();
-        let app = create_router().with_state(state);
+        let mut state = create_test_state();
+        // One client doing all of this would be rate limited long before the end.
+        state.rate_limiter = Arc::new(RateLimiter::new(RateLimits::OFF, RateLimits::OFF));
+        let app = create_router(state);
         let server = TestServer::new(app).unwrap();
         
         let start = std::time::Instant::now();
@@ -581,7 +3102,7 @@ mod tests {
             assert_eq!(response.status_code(), 202);
             
             // Retrieve data
-            let response = server.post(&format!("/pin/{}/{}", namespace, pin_response.pin)).await;
+            let response = poll(&server, &format!("/pin/{}/{}", namespace, pin_response.pin), &pin_response).await;
             assert_eq!(response.status_code(), 200);
             let poll_response: PinResponse = response.json();
             assert!(poll_response.result.is_some());
@@ -602,17 +3123,16 @@ mod tests {
         let mut pins = Vec::new();
         for i in 0..1000 {
             let namespace = format!("memory_{}", i % 50);
-            if let Some(pin) = create_unique_pin(&namespace, &state) {
-                pins.push((namespace, pin));
+            if let Some(new_pin) = create_unique_pin(&namespace, None, &state).unwrap() {
+                pins.push((namespace, new_pin.item.pin));
             }
         }
         
         assert!(pins.len() >= 950, "Should be able to create most PINs");
         
-        // Verify that evmap can handle this load
+        // Verify that the store can handle this load
         for (namespace, pin) in &pins[..100] {
-            let key = create_key(namespace, pin);
-            assert!(state.read.contains_key(&key), "PIN should exist in map");
+            assert!(state.store.get(namespace, pin).unwrap().is_some(), "PIN should exist in store");
         }
         
         println!("Successfully created and verified {} PINs", pins.len());
@@ -630,21 +3150,17 @@ mod tests {
                 let namespace = format!("scale_ns_{}", i);
                 
                 // Create PIN using direct function calls
-                let pin = create_unique_pin(&namespace, &state_clone).unwrap();
+                let pin = create_unique_pin(&namespace, None, &state_clone).unwrap().unwrap().item.pin;
                 
                 // Submit data directly
-                let key = create_key(&namespace, &pin);
                 let mut test_data = HashMap::new();
                 test_data.insert("namespace_id".to_string(), serde_json::Value::Number(i.into()));
                 
-                {
-                    let mut write_handle = state_clone.write.lock().unwrap();
-                    write_handle.update(key.clone(), PinItem::new(pin.clone(), Some(test_data.clone())));
-                    write_handle.refresh();
-                }
+                let answered = update_pin_if_exists(&namespace, &pin, test_data.into(), &state_clone).unwrap();
+                assert!(matches!(answered, Change::Applied(_)));
                 
                 // Retrieve data
-                let retrieved = get_and_remove_pin_if_populated(&namespace, &pin, &state_clone);
+                let retrieved = take_pin_if_populated(&namespace, &pin, Utc::now(), &state_clone).unwrap();
                 assert!(retrieved.is_some());
                 let result = retrieved.unwrap();
                 
@@ -0,0 +1,202 @@
+//! Hashcash-style proof of work for creating pins, so that filling a
+//! namespace costs its creator real computation.
+//!
+//! The server hands out a random challenge with a difficulty in bits. The
+//! client finds a nonce for which `SHA-256("<challenge>:<nonce>")` starts with
+//! that many zero bits, and presents `<challenge>:<nonce>` with its request.
+
+use crate::token;
+use chrono::prelude::{DateTime, Utc};
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+
+/// How hard the challenges are. Difficulty scales from `min_difficulty` for an
+/// empty namespace to `max_difficulty` for a full one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowPolicy {
+    /// Leading zero bits asked of every solution. Proof of work is off when 0.
+    pub min_difficulty: u32,
+    pub max_difficulty: u32,
+    /// How long a challenge can be used for.
+    pub challenge_secs: u32,
+}
+
+impl Default for PowPolicy {
+    fn default() -> Self {
+        PowPolicy {
+            min_difficulty: 0,
+            max_difficulty: 24,
+            challenge_secs: 2 * 60,
+        }
+    }
+}
+
+/// A challenge as handed to the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+    pub expires_at: DateTime<Utc>,
+}
+
+struct Issued {
+    namespace: String,
+    difficulty: u32,
+    expires_at: DateTime<Utc>,
+}
+
+/// Challenges handed out and not yet used. Each can be used once, for the
+/// namespace it was issued for.
+pub struct ProofOfWork {
+    policy: PowPolicy,
+    issued: DashMap<String, Issued>,
+}
+
+impl ProofOfWork {
+    pub fn new(policy: PowPolicy) -> Self {
+        ProofOfWork {
+            policy,
+            issued: DashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.policy.min_difficulty > 0
+    }
+
+    /// The difficulty for a namespace with `occupancy` (0 to 1) of its pins taken.
+    pub fn difficulty(&self, occupancy: f64) -> u32 {
+        let PowPolicy {
+            min_difficulty,
+            max_difficulty,
+            ..
+        } = self.policy;
+        let extra = f64::from(max_difficulty.saturating_sub(min_difficulty));
+        min_difficulty + (extra * occupancy.clamp(0.0, 1.0)).round() as u32
+    }
+
+    /// A fresh challenge for creating a pin in `namespace`.
+    pub fn issue(&self, namespace: &str, occupancy: f64, now: DateTime<Utc>) -> Challenge {
+        let challenge = Challenge {
+            challenge: token::generate(),
+            difficulty: self.difficulty(occupancy),
+            expires_at: now + chrono::Duration::seconds(self.policy.challenge_secs.into()),
+        };
+        self.issued.insert(
+            challenge.challenge.clone(),
+            Issued {
+                namespace: namespace.to_string(),
+                difficulty: challenge.difficulty,
+                expires_at: challenge.expires_at,
+            },
+        );
+        challenge
+    }
+
+    /// Whether `stamp` solves a live challenge issued for `namespace`. A
+    /// challenge is used up by its first valid solution.
+    pub fn verify(&self, namespace: &str, stamp: &str, now: DateTime<Utc>) -> bool {
+        let Some((challenge, _nonce)) = stamp.split_once(':') else {
+            return false;
+        };
+        self.issued
+            .remove_if(challenge, |_, issued| {
+                issued.namespace == namespace
+                    && issued.expires_at > now
+                    && leading_zero_bits(stamp) >= issued.difficulty
+            })
+            .is_some()
+    }
+
+    /// Forgets challenges that expired unused.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        self.issued.retain(|_, issued| issued.expires_at > now);
+    }
+
+    /// Number of challenges waiting to be used.
+    pub fn len(&self) -> usize {
+        self.issued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issued.is_empty()
+    }
+}
+
+/// Finds a stamp for `challenge`, the way a client would.
+pub fn solve(challenge: &str, difficulty: u32) -> String {
+    (0u64..)
+        .map(|nonce| format!("{}:{:x}", challenge, nonce))
+        .find(|stamp| leading_zero_bits(stamp) >= difficulty)
+        .expect("some nonce solves any difficulty up to 256 bits")
+}
+
+fn leading_zero_bits(stamp: &str) -> u32 {
+    let mut bits = 0;
+    for byte in Sha256::digest(stamp.as_bytes()) {
+        bits += byte.leading_zeros();
+        if byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pow() -> ProofOfWork {
+        ProofOfWork::new(PowPolicy {
+            min_difficulty: 8,
+            max_difficulty: 12,
+            challenge_secs: 60,
+        })
+    }
+
+    #[test]
+    fn test_solutions_are_single_use() {
+        let pow = pow();
+        let now = Utc::now();
+        let challenge = pow.issue("ns", 0.0, now);
+        let stamp = solve(&challenge.challenge, challenge.difficulty);
+        assert!(leading_zero_bits(&stamp) >= 8);
+
+        // Not for another namespace, nor with a nonce that misses.
+        assert!(!pow.verify("other", &stamp, now));
+        let miss = (0u64..)
+            .map(|nonce| format!("{}:{:x}", challenge.challenge, nonce))
+            .find(|stamp| leading_zero_bits(stamp) == 0)
+            .unwrap();
+        assert!(!pow.verify("ns", &miss, now));
+        assert!(!pow.verify("ns", "garbage", now));
+
+        assert!(pow.verify("ns", &stamp, now));
+        assert!(!pow.verify("ns", &stamp, now));
+        assert!(pow.is_empty());
+    }
+
+    #[test]
+    fn test_challenges_expire() {
+        let pow = pow();
+        let now = Utc::now();
+        let challenge = pow.issue("ns", 0.0, now);
+        let stamp = solve(&challenge.challenge, challenge.difficulty);
+        let later = now + chrono::Duration::seconds(60);
+        assert!(!pow.verify("ns", &stamp, later));
+
+        pow.prune(later);
+        assert!(pow.is_empty());
+    }
+
+    #[test]
+    fn test_difficulty_follows_occupancy() {
+        let pow = pow();
+        assert!(pow.is_enabled());
+        assert_eq!(pow.difficulty(0.0), 8);
+        assert_eq!(pow.difficulty(0.5), 10);
+        assert_eq!(pow.difficulty(1.0), 12);
+        assert_eq!(pow.issue("ns", 0.75, Utc::now()).difficulty, 11);
+        assert!(!ProofOfWork::new(PowPolicy::default()).is_enabled());
+    }
+}
@@ -0,0 +1,142 @@
+//! Reverse proxies trusted to say which client they forward for.
+
+use anyhow::Context;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Addresses and networks of trusted proxies, written as
+/// `10.0.0.1,192.168.0.0/16,fd00::/8`. Empty unless configured, in which
+/// case the connecting address is always the client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut networks = Vec::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (address, prefix) = match entry.split_once('/') {
+                Some((address, prefix)) => (address, Some(prefix)),
+                None => (entry, None),
+            };
+            let address: IpAddr = address
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid proxy address {:?}", entry))?;
+            let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|&prefix| prefix <= max_prefix)
+                    .with_context(|| format!("Invalid network prefix in {:?}", entry))?,
+                None => max_prefix,
+            };
+            networks.push((address, prefix));
+        }
+        Ok(TrustedProxies(networks))
+    }
+}
+
+impl TrustedProxies {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|&(network, prefix)| match (ip, network) {
+            (IpAddr::V4(ip), IpAddr::V4(network)) => {
+                same_prefix(u32::from(ip).into(), u32::from(network).into(), prefix, 32)
+            }
+            (IpAddr::V6(ip), IpAddr::V6(network)) => {
+                same_prefix(u128::from(ip), u128::from(network), prefix, 128)
+            }
+            _ => false,
+        })
+    }
+
+    /// The client a request from `peer` was made by. Only a trusted proxy may
+    /// say who it forwards for, so `X-Forwarded-For` is read from the right,
+    /// past each proxy we trust, and the first address no trusted proxy is
+    /// known by is the client. Anything further left was written by the
+    /// client itself, and is ignored.
+    pub fn client_ip(&self, peer: IpAddr, forwarded_for: &str) -> IpAddr {
+        let mut client = peer.to_canonical();
+        let mut hops = forwarded_for.rsplit(',').map(str::trim);
+        while self.contains(client) {
+            match hops.next().map(str::parse::<IpAddr>) {
+                Some(Ok(hop)) => client = hop.to_canonical(),
+                // A proxy we trust sent nothing usable, so it is the best we know.
+                _ => break,
+            }
+        }
+        client
+    }
+}
+
+fn same_prefix(a: u128, b: u128, prefix: u8, bits: u32) -> bool {
+    let ignored = bits - u32::from(prefix);
+    a.checked_shr(ignored).unwrap_or(0) == b.checked_shr(ignored).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_peers_are_the_client() {
+        let proxies = TrustedProxies::default();
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.7"), "198.51.100.1"),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxies_name_the_client() {
+        let proxies: TrustedProxies = "10.0.0.0/8, 192.168.1.5".parse().unwrap();
+        assert_eq!(
+            proxies.client_ip(ip("10.1.2.3"), "198.51.100.1"),
+            ip("198.51.100.1")
+        );
+        // Addresses the client made up itself, left of what our proxies added, are ignored.
+        assert_eq!(
+            proxies.client_ip(ip("10.1.2.3"), "1.2.3.4, 198.51.100.1, 192.168.1.5"),
+            ip("198.51.100.1")
+        );
+        // A trusted proxy that does not say who it forwards for is the client.
+        assert_eq!(proxies.client_ip(ip("10.1.2.3"), ""), ip("10.1.2.3"));
+        assert_eq!(proxies.client_ip(ip("10.1.2.3"), "unknown"), ip("10.1.2.3"));
+        assert_eq!(
+            proxies.client_ip(ip("::ffff:10.1.2.3"), "198.51.100.1"),
+            ip("198.51.100.1")
+        );
+        // Only the configured proxies are believed.
+        assert_eq!(
+            proxies.client_ip(ip("192.168.1.6"), "198.51.100.1"),
+            ip("192.168.1.6")
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxies_parsing() {
+        let proxies: TrustedProxies = "fd00::/8,0.0.0.0/0".parse().unwrap();
+        assert!(proxies.contains(ip("fd12::1")));
+        assert!(!proxies.contains(ip("fe80::1")));
+        assert!(proxies.contains(ip("203.0.113.7")));
+        assert!("".parse::<TrustedProxies>().unwrap().is_empty());
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("proxy.local".parse::<TrustedProxies>().is_err());
+    }
+}
@@ -0,0 +1,328 @@
+//! Token-bucket limits on how often one client, and all clients of one
+//! namespace together, may create, poll or submit to pins.
+
+use chrono::prelude::{DateTime, Utc};
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// What a request is charged against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Issuing pins, and revoking them.
+    Create,
+    /// Anything a receiver does to look at a pin.
+    Poll,
+    /// Anything a sender does to a pin.
+    Submit,
+}
+
+/// A budget of `burst` requests, refilled at `burst` per `period_secs`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimit {
+    Off,
+    Budget { burst: u32, period_secs: u32 },
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    /// Parses `off`, or `<requests>/<seconds>` such as `30/60`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("off") {
+            return Ok(RateLimit::Off);
+        }
+        let Some((burst, period_secs)) = s.split_once('/') else {
+            anyhow::bail!("Expected off or requests/seconds, got {:?}", s);
+        };
+        let (Ok(burst), Ok(period_secs)) = (burst.trim().parse(), period_secs.trim().parse())
+        else {
+            anyhow::bail!("Invalid rate limit {:?}", s);
+        };
+        if burst == 0 || period_secs == 0 {
+            anyhow::bail!(
+                "Rate limit {:?} needs a nonzero number of requests and seconds",
+                s
+            );
+        }
+        Ok(RateLimit::Budget { burst, period_secs })
+    }
+}
+
+/// The budget for each kind of request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimits {
+    pub create: RateLimit,
+    pub poll: RateLimit,
+    pub submit: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            create: RateLimit::Budget {
+                burst: 30,
+                period_secs: 60,
+            },
+            // Receivers that do not long-poll check back every second or so.
+            poll: RateLimit::Budget {
+                burst: 120,
+                period_secs: 60,
+            },
+            submit: RateLimit::Budget {
+                burst: 30,
+                period_secs: 60,
+            },
+        }
+    }
+}
+
+impl RateLimits {
+    /// No limits at all, the default for namespaces.
+    pub const OFF: RateLimits = RateLimits {
+        create: RateLimit::Off,
+        poll: RateLimit::Off,
+        submit: RateLimit::Off,
+    };
+
+    fn get(&self, operation: Operation) -> RateLimit {
+        match operation {
+            Operation::Create => self.create,
+            Operation::Poll => self.poll,
+            Operation::Submit => self.submit,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: DateTime<Utc>,
+}
+
+/// Whose budget a bucket holds.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Subject {
+    /// One client across every namespace. Requests whose address is unknown
+    /// share a bucket.
+    Client(Option<IpAddr>),
+    /// Every client of one namespace together.
+    Namespace(String),
+}
+
+/// One bucket per operation and client, and another per operation and
+/// namespace. A request must fit in both, so neither spreading requests
+/// over namespaces nor over addresses gets around the limits.
+pub struct RateLimiter {
+    client_limits: RateLimits,
+    namespace_limits: RateLimits,
+    buckets: DashMap<(Operation, Subject), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(client_limits: RateLimits, namespace_limits: RateLimits) -> Self {
+        RateLimiter {
+            client_limits,
+            namespace_limits,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes one request from the client's budget and the namespace's, or says
+    /// how long until both have room for it.
+    pub fn check(
+        &self,
+        operation: Operation,
+        client: Option<IpAddr>,
+        namespace: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), Duration> {
+        let client = (operation, Subject::Client(client));
+        self.take(&client, now)?;
+        let namespace = (operation, Subject::Namespace(namespace.to_string()));
+        if let Err(wait) = self.take(&namespace, now) {
+            // The request is refused, so it does not count against the client.
+            self.give_back(&client);
+            return Err(wait);
+        }
+        Ok(())
+    }
+
+    fn limit(&self, (operation, subject): &(Operation, Subject)) -> RateLimit {
+        match subject {
+            Subject::Client(_) => self.client_limits.get(*operation),
+            Subject::Namespace(_) => self.namespace_limits.get(*operation),
+        }
+    }
+
+    fn take(&self, key: &(Operation, Subject), now: DateTime<Utc>) -> Result<(), Duration> {
+        let RateLimit::Budget { burst, period_secs } = self.limit(key) else {
+            return Ok(());
+        };
+        let per_sec = f64::from(burst) / f64::from(period_secs);
+        let mut bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: f64::from(burst),
+            refilled_at: now,
+        });
+        refill(&mut bucket, f64::from(burst), per_sec, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    fn give_back(&self, key: &(Operation, Subject)) {
+        if let Some(mut bucket) = self.buckets.get_mut(key) {
+            bucket.tokens += 1.0;
+        }
+    }
+
+    /// Forgets buckets that have refilled completely, which behave the same
+    /// as ones that were never created.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        self.buckets.retain(|key, bucket| {
+            let RateLimit::Budget { burst, period_secs } = self.limit(key) else {
+                return false;
+            };
+            let per_sec = f64::from(burst) / f64::from(period_secs);
+            refill(bucket, f64::from(burst), per_sec, now);
+            bucket.tokens < f64::from(burst)
+        });
+    }
+
+    /// Number of buckets currently being tracked, across operations, clients and namespaces.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+fn refill(bucket: &mut Bucket, burst: f64, per_sec: f64, now: DateTime<Utc>) {
+    let elapsed = (now - bucket.refilled_at).to_std().unwrap_or_default();
+    bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * per_sec).min(burst);
+    bucket.refilled_at = now;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(
+            RateLimits {
+                create: "2/10".parse().unwrap(),
+                poll: "off".parse().unwrap(),
+                submit: "1/1".parse().unwrap(),
+            },
+            RateLimits::OFF,
+        )
+    }
+
+    #[test]
+    fn test_budget_refills_over_time() {
+        let limiter = limiter();
+        let client = Some("10.0.0.1".parse().unwrap());
+        let now = Utc::now();
+        assert!(limiter.check(Operation::Create, client, "ns", now).is_ok());
+        assert!(limiter.check(Operation::Create, client, "ns", now).is_ok());
+        assert_eq!(
+            limiter.check(Operation::Create, client, "ns", now),
+            Err(Duration::from_secs(5))
+        );
+
+        let later = now + chrono::Duration::seconds(5);
+        assert!(limiter
+            .check(Operation::Create, client, "ns", later)
+            .is_ok());
+        assert!(limiter
+            .check(Operation::Create, client, "ns", later)
+            .is_err());
+    }
+
+    #[test]
+    fn test_budgets_are_separate() {
+        let limiter = limiter();
+        let client = Some("10.0.0.1".parse().unwrap());
+        let other = Some("10.0.0.2".parse().unwrap());
+        let now = Utc::now();
+        assert!(limiter.check(Operation::Submit, client, "ns", now).is_ok());
+        assert!(limiter.check(Operation::Submit, client, "ns", now).is_err());
+        assert!(limiter.check(Operation::Submit, other, "ns", now).is_ok());
+        // Moving to another namespace does not get a client a fresh budget.
+        assert!(limiter
+            .check(Operation::Submit, client, "other", now)
+            .is_err());
+        assert!(limiter.check(Operation::Create, client, "ns", now).is_ok());
+        for _ in 0..100 {
+            assert!(limiter.check(Operation::Poll, client, "ns", now).is_ok());
+        }
+
+        limiter.prune(now + chrono::Duration::seconds(1));
+        assert_eq!(limiter.len(), 1);
+        limiter.prune(now + chrono::Duration::seconds(10));
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn test_namespaces_have_a_budget_of_their_own() {
+        let limiter = RateLimiter::new(
+            RateLimits {
+                create: "2/10".parse().unwrap(),
+                ..RateLimits::OFF
+            },
+            RateLimits {
+                create: "3/10".parse().unwrap(),
+                ..RateLimits::OFF
+            },
+        );
+        let now = Utc::now();
+        let clients: Vec<Option<IpAddr>> = (1..=4)
+            .map(|i| Some(format!("10.0.0.{}", i).parse().unwrap()))
+            .collect();
+        assert!(limiter
+            .check(Operation::Create, clients[0], "ns", now)
+            .is_ok());
+        assert!(limiter
+            .check(Operation::Create, clients[1], "ns", now)
+            .is_ok());
+        assert!(limiter
+            .check(Operation::Create, clients[2], "ns", now)
+            .is_ok());
+        // Spreading requests over addresses does not get around the namespace's budget.
+        assert_eq!(
+            limiter.check(Operation::Create, clients[3], "ns", now),
+            Err(Duration::from_secs_f64(10.0 / 3.0))
+        );
+        assert!(limiter
+            .check(Operation::Create, clients[3], "other", now)
+            .is_ok());
+        // The refused request was not charged to the client either.
+        assert!(limiter
+            .check(Operation::Create, clients[3], "other", now)
+            .is_ok());
+        assert!(limiter
+            .check(Operation::Create, clients[3], "other", now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_parsing() {
+        assert_eq!(
+            "30/60".parse::<RateLimit>().unwrap(),
+            RateLimit::Budget {
+                burst: 30,
+                period_secs: 60
+            }
+        );
+        assert_eq!("OFF".parse::<RateLimit>().unwrap(), RateLimit::Off);
+        assert!("30".parse::<RateLimit>().is_err());
+        assert!("0/60".parse::<RateLimit>().is_err());
+        assert!("lots/60".parse::<RateLimit>().is_err());
+    }
+}
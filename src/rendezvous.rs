@@ -0,0 +1,140 @@
+//! Pairs up the two parties talking over a pin's WebSocket, relaying each
+//! one's messages to the other.
+
+use crate::store::create_key;
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Messages a party can send before the other has read them, including ones
+/// sent before the other side has joined.
+const BACKLOG: usize = 16;
+
+/// The sessions currently open, one per pin.
+#[derive(Default)]
+pub struct Rendezvous {
+    rooms: DashMap<String, Room>,
+}
+
+/// Which side of the session a party is on. Like answering and collecting a
+/// pin, the sender only needs the pin and the receiver needs its secret.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+type Ends = (mpsc::Receiver<String>, mpsc::Sender<String>);
+
+struct Room {
+    /// The sender's ends of the channels, until the sender joins.
+    sender: Option<Ends>,
+    /// The receiver's ends of the channels, until the receiver joins.
+    receiver: Option<Ends>,
+    /// Parties who joined and have not left yet.
+    occupied: usize,
+}
+
+impl Room {
+    fn new() -> Self {
+        let (to_sender, sender_inbox) = mpsc::channel(BACKLOG);
+        let (to_receiver, receiver_inbox) = mpsc::channel(BACKLOG);
+        Room {
+            sender: Some((sender_inbox, to_receiver)),
+            receiver: Some((receiver_inbox, to_sender)),
+            occupied: 0,
+        }
+    }
+}
+
+impl Rendezvous {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the `role` seat in the pin's session, opening it if nobody is
+    /// there yet. None if somebody already took that seat.
+    pub fn join(self: &Arc<Self>, namespace: &str, pin: &str, role: Role) -> Option<Seat> {
+        let key = create_key(namespace, pin);
+        let mut room = self.rooms.entry(key.clone()).or_insert_with(Room::new);
+        let (inbox, outbox) = match role {
+            Role::Sender => room.sender.take()?,
+            Role::Receiver => room.receiver.take()?,
+        };
+        room.occupied += 1;
+        Some(Seat {
+            rendezvous: self.clone(),
+            key,
+            inbox,
+            outbox,
+        })
+    }
+
+    /// Number of pins with a session open.
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+}
+
+/// One party's end of a session. Leaving, by dropping it, ends the session
+/// for the other party too.
+pub struct Seat {
+    rendezvous: Arc<Rendezvous>,
+    key: String,
+    /// Messages from the other party. Ends once they leave.
+    pub inbox: mpsc::Receiver<String>,
+    /// Messages to the other party. Fails once they leave.
+    pub outbox: mpsc::Sender<String>,
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        // The session closes with its last party, so the pin can host a new one.
+        self.rendezvous.rooms.remove_if_mut(&self.key, |_, room| {
+            room.occupied -= 1;
+            room.occupied == 0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_two_parties_exchange_messages() {
+        let rendezvous = Arc::new(Rendezvous::new());
+        let mut receiver = rendezvous.join("ns", "ABCD", Role::Receiver).unwrap();
+        // Sent before anyone is there to read it.
+        receiver.outbox.send("offer".to_string()).await.unwrap();
+        assert!(rendezvous.join("ns", "ABCD", Role::Receiver).is_none());
+
+        let mut sender = rendezvous.join("ns", "ABCD", Role::Sender).unwrap();
+        assert!(rendezvous.join("ns", "ABCD", Role::Sender).is_none());
+        assert_eq!(sender.inbox.recv().await.unwrap(), "offer");
+        sender.outbox.send("answer".to_string()).await.unwrap();
+        assert_eq!(receiver.inbox.recv().await.unwrap(), "answer");
+
+        drop(receiver);
+        assert_eq!(sender.inbox.recv().await, None);
+        assert!(sender.outbox.send("ack".to_string()).await.is_err());
+        drop(sender);
+        assert!(rendezvous.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_closes_if_first_party_leaves_alone() {
+        let rendezvous = Arc::new(Rendezvous::new());
+        drop(rendezvous.join("ns", "ABCD", Role::Sender).unwrap());
+        assert!(rendezvous.is_empty());
+
+        // A later pair starts over rather than finding a dead session.
+        let _sender = rendezvous.join("ns", "ABCD", Role::Sender).unwrap();
+        let _receiver = rendezvous.join("ns", "ABCD", Role::Receiver).unwrap();
+        assert_eq!(rendezvous.len(), 1);
+    }
+}
@@ -0,0 +1,308 @@
+//! Encryption of pin results at rest, so that memory dumps, snapshots and
+//! databases only ever hold ciphertext.
+//!
+//! Each result is sealed with XChaCha20-Poly1305 under a key derived with
+//! HKDF-SHA256 from a master key and the pin's namespace and pin, and records
+//! the ID of the master key it was sealed with. Rotating keys means putting a
+//! new one first and keeping the old ones until the pins they sealed have
+//! ended.
+
+use anyhow::Context;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand::{rng, Rng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+/// Room reserved for a serialized result, so that writing one of the sizes
+/// the service accepts never reallocates and leaves an unwiped copy behind.
+const PLAINTEXT_CAPACITY: usize = 4096;
+/// Separates these keys from anything else derived from the same master key.
+const KEY_INFO: &[u8] = b"configgymajiggy pin result v1";
+
+/// A pin's result as held in the store.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SealedResult {
+    key_id: String,
+    #[serde(with = "hex_bytes")]
+    nonce: Vec<u8>,
+    /// Wiped from memory when the result is consumed, revoked or expires.
+    #[serde(with = "hex_bytes")]
+    ciphertext: Zeroizing<Vec<u8>>,
+}
+
+/// A result in the clear, as submitted or as opened for the receiver. Its
+/// strings are wiped from memory when it is dropped.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Plaintext(HashMap<String, Value>);
+
+impl Deref for Plaintext {
+    type Target = HashMap<String, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<HashMap<String, Value>> for Plaintext {
+    fn from(result: HashMap<String, Value>) -> Self {
+        Plaintext(result)
+    }
+}
+
+impl Drop for Plaintext {
+    fn drop(&mut self) {
+        for (mut key, mut value) in self.0.drain() {
+            key.zeroize();
+            wipe(&mut value);
+        }
+    }
+}
+
+/// Zeroes every string in `value`, keys included. Numbers and booleans are
+/// left as they are.
+fn wipe(value: &mut Value) {
+    match value {
+        Value::String(text) => text.zeroize(),
+        Value::Array(items) => items.iter_mut().for_each(wipe),
+        Value::Object(fields) => {
+            for (mut key, mut field) in std::mem::take(fields) {
+                key.zeroize();
+                wipe(&mut field);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+/// The master keys results are sealed with, by ID. The first seals new
+/// results; the rest only open results sealed before it was added.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<(String, Zeroizing<[u8; KEY_LEN]>)>,
+}
+
+impl Keyring {
+    /// A keyring with a single random key, for when none is configured.
+    /// Results it seals cannot be opened after a restart.
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0; KEY_LEN]);
+        rng().fill(&mut key[..]);
+        let id = format!("ephemeral-{}", hex::encode(rng().random::<[u8; 4]>()));
+        Keyring {
+            keys: vec![(id, key)],
+        }
+    }
+
+    /// The ID of the key new results are sealed with.
+    pub fn current_id(&self) -> &str {
+        &self.keys[0].0
+    }
+
+    pub fn seal(
+        &self,
+        namespace: &str,
+        pin: &str,
+        result: &Plaintext,
+    ) -> anyhow::Result<SealedResult> {
+        let (key_id, master) = &self.keys[0];
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(PLAINTEXT_CAPACITY));
+        serde_json::to_writer(&mut *plaintext, result)?;
+        let nonce: [u8; NONCE_LEN] = rng().random();
+        let ciphertext = cipher(master, key_id, namespace, pin)
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| anyhow::anyhow!("Could not seal the result"))?;
+        Ok(SealedResult {
+            key_id: key_id.clone(),
+            nonce: nonce.to_vec(),
+            ciphertext: Zeroizing::new(ciphertext),
+        })
+    }
+
+    /// The result sealed for `namespace` and `pin`, failing if it was sealed
+    /// with a key no longer held, or for another pin, or has been tampered with.
+    pub fn open(
+        &self,
+        namespace: &str,
+        pin: &str,
+        sealed: &SealedResult,
+    ) -> anyhow::Result<Plaintext> {
+        let SealedResult {
+            key_id,
+            nonce,
+            ciphertext,
+        } = sealed;
+        let (_, master) = self
+            .keys
+            .iter()
+            .find(|(id, _)| id == key_id)
+            .with_context(|| format!("No master key {:?} to open the result with", key_id))?;
+        if nonce.len() != NONCE_LEN {
+            anyhow::bail!("Sealed result has a malformed nonce");
+        }
+        let plaintext = cipher(master, key_id, namespace, pin)
+            .decrypt(XNonce::from_slice(nonce), ciphertext.as_slice())
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow::anyhow!("Sealed result failed authentication"))?;
+        Ok(Plaintext(serde_json::from_slice(&plaintext)?))
+    }
+}
+
+/// The cipher for one pin's result, keyed from the master key.
+fn cipher(master: &[u8; KEY_LEN], key_id: &str, namespace: &str, pin: &str) -> XChaCha20Poly1305 {
+    let mut info = KEY_INFO.to_vec();
+    for part in [namespace, pin] {
+        // Length-prefixed, so that no two namespace and pin pairs run together the same.
+        info.extend_from_slice(&(part.len() as u64).to_be_bytes());
+        info.extend_from_slice(part.as_bytes());
+    }
+    let mut key = Zeroizing::new([0; KEY_LEN]);
+    Hkdf::<Sha256>::new(Some(key_id.as_bytes()), master)
+        .expand(&info, &mut key[..])
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    XChaCha20Poly1305::new(key.as_ref().into())
+}
+
+/// Parses `id=<64 hex digits>` entries separated by commas, current key first.
+impl FromStr for Keyring {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys: Vec<(String, Zeroizing<[u8; KEY_LEN]>)> = Vec::new();
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let Some((id, hex_key)) = entry.split_once('=') else {
+                anyhow::bail!("Expected id=key, got an entry without '='");
+            };
+            let id = id.trim();
+            if id.is_empty() {
+                anyhow::bail!("Master key IDs cannot be empty");
+            }
+            if keys.iter().any(|(existing, _)| existing == id) {
+                anyhow::bail!("Master key {:?} is given twice", id);
+            }
+            let mut key = Zeroizing::new([0; KEY_LEN]);
+            hex::decode_to_slice(hex_key.trim(), &mut key[..]).map_err(|_| {
+                anyhow::anyhow!("Master key {:?} must be {} hex digits", id, KEY_LEN * 2)
+            })?;
+            keys.push((id.to_string(), key));
+        }
+        if keys.is_empty() {
+            anyhow::bail!("No master keys given");
+        }
+        Ok(Keyring { keys })
+    }
+}
+
+/// Shows the key IDs only, so configuration can be logged safely.
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field(
+                "ids",
+                &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: From<Vec<u8>>,
+    {
+        let encoded = String::deserialize(deserializer)?;
+        hex::decode(encoded)
+            .map(T::from)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn result() -> Plaintext {
+        Plaintext(HashMap::from([("wifi".to_string(), json!("hunter2"))]))
+    }
+
+    #[test]
+    fn test_results_only_open_for_their_pin() {
+        let keyring = Keyring::generate();
+        let sealed = keyring.seal("ns", "ABCD", &result()).unwrap();
+        let stored = serde_json::to_string(&sealed).unwrap();
+        assert!(!stored.contains("hunter2"));
+
+        let restored: SealedResult = serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored, sealed);
+        assert_eq!(keyring.open("ns", "ABCD", &restored).unwrap(), result());
+        assert!(keyring.open("ns", "WXYZ", &sealed).is_err());
+        assert!(keyring.open("other", "ABCD", &sealed).is_err());
+        assert!(Keyring::generate().open("ns", "ABCD", &sealed).is_err());
+
+        let mut tampered = sealed;
+        tampered.ciphertext[0] ^= 1;
+        assert!(keyring.open("ns", "ABCD", &tampered).is_err());
+
+        // Only sealed records are read, never a result in the clear.
+        assert!(serde_json::from_value::<SealedResult>(json!({"wifi": "hunter2"})).is_err());
+    }
+
+    #[test]
+    fn test_rotated_keys_still_open_older_results() {
+        let old_key = "11".repeat(KEY_LEN);
+        let new_key = "22".repeat(KEY_LEN);
+        let before: Keyring = format!("2024={}", old_key).parse().unwrap();
+        let sealed = before.seal("ns", "ABCD", &result()).unwrap();
+
+        let after: Keyring = format!("2025={}, 2024={}", new_key, old_key)
+            .parse()
+            .unwrap();
+        assert_eq!(after.current_id(), "2025");
+        assert_eq!(after.open("ns", "ABCD", &sealed).unwrap(), result());
+        let resealed = after.seal("ns", "ABCD", &result()).unwrap();
+        assert_eq!(resealed.key_id, "2025");
+        assert!(before.open("ns", "ABCD", &resealed).is_err());
+    }
+
+    #[test]
+    fn test_plaintext_strings_are_wiped() {
+        // Objects are emptied, since their keys can only be wiped once taken out.
+        let mut value = json!(["hunter2", {"pin": "1234"}, 80, true]);
+        wipe(&mut value);
+        assert_eq!(value, json!(["", {}, 80, true]));
+    }
+
+    #[test]
+    fn test_keyring_parsing() {
+        let key = "ab".repeat(KEY_LEN);
+        assert!("".parse::<Keyring>().is_err());
+        assert!(key.parse::<Keyring>().is_err());
+        assert!(format!("k1={}", &key[2..]).parse::<Keyring>().is_err());
+        assert!(format!("k1={key},k1={key}").parse::<Keyring>().is_err());
+        let keyring: Keyring = format!("k1={key}").parse().unwrap();
+        assert_eq!(format!("{:?}", keyring), r#"Keyring { ids: ["k1"] }"#);
+    }
+}
@@ -0,0 +1,479 @@
+use super::snapshot::Snapshot;
+use super::wal::{FsyncPolicy, Wal, WalRecord};
+use super::{create_key, PinItem, PinStore};
+use anyhow::Context;
+use chrono::prelude::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+
+/// The default backend: a sharded map held in memory, optionally persisted
+/// through periodic snapshots and a write-ahead log.
+///
+/// Each operation locks only the shard holding its key, so requests for
+/// different pins proceed in parallel.
+pub struct MemoryStore {
+    pins: DashMap<String, PinItem>,
+    snapshot_path: Option<PathBuf>,
+    wal: Option<Mutex<Wal>>,
+    /// Writers hold this shared while journaling; a checkpoint holds it
+    /// exclusively so no mutation lands between the snapshot and the log reset.
+    checkpoint_gate: RwLock<()>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    /// A store that lives only as long as the process.
+    pub fn new() -> Self {
+        MemoryStore {
+            pins: DashMap::new(),
+            snapshot_path: None,
+            wal: None,
+            checkpoint_gate: RwLock::new(()),
+        }
+    }
+
+    /// Restores the store from the snapshot at `snapshot_path` and replays the
+    /// log at `wal_path` on top of it. Later checkpoints write back to the same
+    /// snapshot, compacting the log if there is one.
+    pub fn open(
+        snapshot_path: &Path,
+        wal_path: Option<&Path>,
+        fsync: FsyncPolicy,
+    ) -> anyhow::Result<Self> {
+        let mut store = Self::new();
+        if let Some(snapshot) = Snapshot::read(snapshot_path)? {
+            info!(
+                "Restoring {} pins from {}",
+                snapshot.entries.len(),
+                snapshot_path.display()
+            );
+            store.pins.extend(snapshot.into_items());
+        }
+        if let Some(wal_path) = wal_path {
+            let (wal, records) = Wal::open(wal_path, fsync)?;
+            info!(
+                "Replaying {} write-ahead log records from {}",
+                records.len(),
+                wal_path.display()
+            );
+            for record in records {
+                apply(&store.pins, record);
+            }
+            store.wal = Some(Mutex::new(wal));
+        }
+        store.snapshot_path = Some(snapshot_path.to_path_buf());
+        Ok(store)
+    }
+
+    /// Held across a mutation and its log record. Without a log there is
+    /// nothing for a checkpoint to keep in step with, so no lock is taken.
+    fn write_gate(&self) -> anyhow::Result<Option<RwLockReadGuard<'_, ()>>> {
+        if self.wal.is_none() {
+            return Ok(None);
+        }
+        self.checkpoint_gate
+            .read()
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("Checkpoint lock poisoned"))
+    }
+
+    // Callers hold the key's shard lock, so records for a pin land in the same
+    // order as its map updates. Records for different pins commute on replay.
+    // A mutation that could not be journaled must not be applied either.
+    fn journal(&self, record: impl FnOnce() -> WalRecord) -> anyhow::Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut wal = wal
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Write-ahead log lock poisoned"))?;
+        wal.append(&record())
+    }
+
+    fn items(&self) -> Vec<(String, PinItem)> {
+        self.pins
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+}
+
+fn apply(pins: &DashMap<String, PinItem>, record: WalRecord) {
+    match record {
+        WalRecord::Insert { key, item } | WalRecord::Update { key, item } => {
+            pins.insert(key, item);
+        }
+        WalRecord::Expire { key } => {
+            pins.remove(&key);
+        }
+    }
+}
+
+impl PinStore for MemoryStore {
+    fn create_if_absent(&self, namespace: &str, item: PinItem) -> anyhow::Result<bool> {
+        let _gate = self.write_gate()?;
+        match self.pins.entry(create_key(namespace, &item.pin)) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(slot) => {
+                self.journal(|| WalRecord::Insert {
+                    key: slot.key().clone(),
+                    item: item.clone(),
+                })?;
+                slot.insert(item);
+                Ok(true)
+            }
+        }
+    }
+
+    fn update(
+        &self,
+        namespace: &str,
+        pin: &str,
+        update: &mut dyn FnMut(&mut PinItem) -> bool,
+    ) -> anyhow::Result<Option<PinItem>> {
+        let _gate = self.write_gate()?;
+        let Some(mut stored) = self.pins.get_mut(&create_key(namespace, pin)) else {
+            return Ok(None);
+        };
+        let mut item = stored.clone();
+        if !update(&mut item) {
+            return Ok(None);
+        }
+        self.journal(|| WalRecord::Update {
+            key: stored.key().clone(),
+            item: item.clone(),
+        })?;
+        *stored = item.clone();
+        Ok(Some(item))
+    }
+
+    fn expire_all_due(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<(String, String)>> {
+        let stale: Vec<String> = self
+            .pins
+            .iter()
+            .filter(|entry| entry.purge_at() <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+        let _gate = self.write_gate()?;
+        let mut expired = Vec::new();
+        for key in stale {
+            // Re-check under the shard lock in case the pin was answered meanwhile.
+            let Entry::Occupied(entry) = self.pins.entry(key) else {
+                continue;
+            };
+            if entry.get().purge_at() > now {
+                continue;
+            }
+            self.journal(|| WalRecord::Expire {
+                key: entry.key().clone(),
+            })?;
+            let (key, item) = entry.remove_entry();
+            if let Some((namespace, _)) = key.rsplit_once(':') {
+                expired.push((namespace.to_string(), item.pin));
+            }
+        }
+        Ok(expired)
+    }
+
+    fn expire_if_due(
+        &self,
+        namespace: &str,
+        pin: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<bool> {
+        let _gate = self.write_gate()?;
+        let Entry::Occupied(entry) = self.pins.entry(create_key(namespace, pin)) else {
+            return Ok(false);
+        };
+        if entry.get().purge_at() > now {
+            return Ok(false);
+        }
+        self.journal(|| WalRecord::Expire {
+            key: entry.key().clone(),
+        })?;
+        entry.remove();
+        Ok(true)
+    }
+
+    fn deadlines(&self) -> anyhow::Result<Vec<(String, String, DateTime<Utc>)>> {
+        Ok(self
+            .pins
+            .iter()
+            .filter_map(|entry| {
+                let (namespace, _) = entry.key().rsplit_once(':')?;
+                Some((namespace.to_string(), entry.pin.clone(), entry.purge_at()))
+            })
+            .collect())
+    }
+
+    fn get(&self, namespace: &str, pin: &str) -> anyhow::Result<Option<PinItem>> {
+        Ok(self
+            .pins
+            .get(&create_key(namespace, pin))
+            .map(|item| item.clone()))
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Write-ahead log lock poisoned"))?;
+            wal.sync_if_due()?;
+        }
+        Ok(())
+    }
+
+    /// Writes a snapshot and, if there is a log, truncates it. Writers are
+    /// blocked for the duration so no mutation falls between the two.
+    fn checkpoint(&self) -> anyhow::Result<()> {
+        let Some(snapshot_path) = &self.snapshot_path else {
+            return Ok(());
+        };
+        let _gate = self
+            .checkpoint_gate
+            .write()
+            .map_err(|_| anyhow::anyhow!("Checkpoint lock poisoned"))?;
+        let snapshot = Snapshot::from_items(self.items());
+        snapshot.write_atomic(snapshot_path)?;
+        if let Some(wal) = &self.wal {
+            let mut wal = wal
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Write-ahead log lock poisoned"))?;
+            wal.reset()
+                .with_context(|| format!("Could not truncate {}", wal.path().display()))?;
+        }
+        info!(
+            "Snapshotted {} pins to {}",
+            snapshot.entries.len(),
+            snapshot_path.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seal::Keyring;
+    use crate::store::conformance;
+    use chrono::Duration;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn fill(store: &MemoryStore, namespace: &str, pin: &str) {
+        let mut data = HashMap::new();
+        data.insert("token".to_string(), json!("abc"));
+        let data = Keyring::generate()
+            .seal(namespace, pin, &data.into())
+            .unwrap();
+        store
+            .update(namespace, pin, &mut |item| {
+                item.result = Some(data.clone());
+                true
+            })
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(|| Arc::new(MemoryStore::new()));
+    }
+
+    #[test]
+    fn test_restart_from_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("pins.json");
+
+        let store = MemoryStore::open(&snapshot_path, None, FsyncPolicy::Always).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        fill(&store, "ns", "ABCD");
+        store.checkpoint().unwrap();
+        // Not in the snapshot, so lost without a log.
+        store
+            .create_if_absent("ns", PinItem::new("WXYZ".to_string(), None, Utc::now()))
+            .unwrap();
+        drop(store);
+
+        let store = MemoryStore::open(&snapshot_path, None, FsyncPolicy::Always).unwrap();
+        assert!(store.get("ns", "ABCD").unwrap().unwrap().result.is_some());
+        assert!(store.get("ns", "WXYZ").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restart_replays_log_over_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("pins.json");
+        let wal_path = dir.path().join("pins.wal");
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("KEPT".to_string(), None, Utc::now()))
+            .unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("TAKE".to_string(), None, Utc::now()))
+            .unwrap();
+        store.checkpoint().unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+
+        store
+            .create_if_absent("ns", PinItem::new("LATE".to_string(), None, Utc::now()))
+            .unwrap();
+        fill(&store, "ns", "TAKE");
+        store.take_if_populated("ns", "TAKE", Utc::now()).unwrap();
+        fill(&store, "ns", "KEPT");
+        drop(store);
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        assert!(store.get("ns", "KEPT").unwrap().unwrap().result.is_some());
+        assert!(store.get("ns", "LATE").unwrap().is_some());
+        let taken = store.get("ns", "TAKE").unwrap().unwrap();
+        assert!(taken.consumed_at.is_some());
+        assert!(taken.result.is_none());
+    }
+
+    #[test]
+    fn test_restart_after_torn_log_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("pins.json");
+        let wal_path = dir.path().join("pins.wal");
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        let intact_len = std::fs::metadata(&wal_path).unwrap().len();
+        fill(&store, "ns", "ABCD");
+        drop(store);
+
+        // Simulate a crash halfway through appending the update.
+        let full_len = std::fs::metadata(&wal_path).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&wal_path)
+            .unwrap();
+        file.set_len(intact_len + (full_len - intact_len) / 2)
+            .unwrap();
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        let item = store.get("ns", "ABCD").unwrap().unwrap();
+        assert!(item.result.is_none());
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), intact_len);
+    }
+
+    #[test]
+    fn test_writes_are_refused_when_the_log_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("pins.json");
+        let wal_path = dir.path().join("pins.wal");
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        let intact_len = std::fs::metadata(&wal_path).unwrap().len();
+        store
+            .wal
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .fail_writes()
+            .unwrap();
+
+        // Nothing that could not be journaled is applied.
+        assert!(store
+            .create_if_absent("ns", PinItem::new("WXYZ".to_string(), None, Utc::now()))
+            .is_err());
+        assert!(store.get("ns", "WXYZ").unwrap().is_none());
+        let answered = store.update("ns", "ABCD", &mut |item| {
+            item.renewals += 1;
+            true
+        });
+        assert!(answered.is_err());
+        assert_eq!(store.get("ns", "ABCD").unwrap().unwrap().renewals, 0);
+        assert!(store.take_if_populated("ns", "ABCD", Utc::now()).is_err());
+        assert!(store
+            .expire_if_due("ns", "ABCD", Utc::now() + Duration::days(1))
+            .is_err());
+        assert!(store.get("ns", "ABCD").unwrap().is_some());
+        drop(store);
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), intact_len);
+        assert!(store.get("ns", "ABCD").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_checkpoint_during_concurrent_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("pins.json");
+        let wal_path = dir.path().join("pins.wal");
+
+        let store = Arc::new(
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Never).unwrap(),
+        );
+        let writers: Vec<_> = (0..4)
+            .map(|thread| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for n in 0..200 {
+                        let pin = format!("{}{:03}", thread, n);
+                        store
+                            .create_if_absent("ns", PinItem::new(pin, None, Utc::now()))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..10 {
+            store.checkpoint().unwrap();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        drop(store);
+
+        // Every pin is in either the snapshot or the log, never neither.
+        let store = MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Never).unwrap();
+        assert_eq!(store.items().len(), 800);
+    }
+
+    #[test]
+    fn test_expire_is_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("pins.json");
+        let wal_path = dir.path().join("pins.wal");
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("OLD1".to_string(), None, Utc::now()))
+            .unwrap();
+        store
+            .expire_all_due(Utc::now() + Duration::minutes(11))
+            .unwrap();
+        drop(store);
+
+        let store =
+            MemoryStore::open(&snapshot_path, Some(&wal_path), FsyncPolicy::Always).unwrap();
+        assert!(store.get("ns", "OLD1").unwrap().is_none());
+    }
+}
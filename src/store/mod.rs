@@ -0,0 +1,637 @@
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::seal::SealedResult;
+use crate::DEFAULT_TTL_SECS;
+use anyhow::Context;
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub mod memory;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod wal;
+
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+pub use memory::MemoryStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+pub struct PinItem {
+    /// When the pin was created or last answered. Its lifetime counts from here.
+    pub timestamp: DateTime<Utc>,
+    pub pin: String,
+    /// Kept sealed, and only opened to hand it to the receiver.
+    pub result: Option<SealedResult>,
+    pub created_at: DateTime<Utc>,
+    pub ttl_secs: u32,
+    /// When the pin goes stale if nobody touches it again.
+    pub expires_at: DateTime<Utc>,
+    pub renewals: u32,
+    /// Hash of the token that lets the creator revoke the pin.
+    pub creator_token_hash: Option<String>,
+    /// Hash of the secret the receiver must present to collect the result.
+    /// Empty if no secret was ever issued, in which case nobody may collect it.
+    pub receiver_secret_hash: String,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// When the receiver took the result.
+    pub consumed_at: Option<DateTime<Utc>>,
+    /// How many times the receiver has polled the pin.
+    pub reads: u32,
+    /// When the receiver last polled the pin.
+    pub last_read_at: Option<DateTime<Utc>>,
+    /// How long the record outlives the pin, so lookups can still tell how it ended.
+    pub tombstone_secs: u32,
+    /// How long a device must wait between token requests. Grows each time
+    /// it asks too soon.
+    pub poll_interval_secs: u32,
+}
+
+/// Where a pin is in its life, as reported to anyone inspecting it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PinState {
+    AwaitingData,
+    Fulfilled,
+    Consumed,
+    Expired,
+    Revoked,
+}
+
+impl PinItem {
+    /// A pin created at `now` with the default lifetime.
+    pub fn new(pin: String, result: Option<SealedResult>, now: DateTime<Utc>) -> Self {
+        PinItem {
+            timestamp: now,
+            pin,
+            result,
+            created_at: now,
+            ttl_secs: DEFAULT_TTL_SECS,
+            expires_at: now + Duration::seconds(DEFAULT_TTL_SECS.into()),
+            renewals: 0,
+            creator_token_hash: None,
+            receiver_secret_hash: String::new(),
+            revoked_at: None,
+            consumed_at: None,
+            reads: 0,
+            last_read_at: None,
+            tombstone_secs: 0,
+            poll_interval_secs: 0,
+        }
+    }
+
+    /// The same pin living `ttl_secs` from its timestamp instead.
+    pub fn with_ttl(mut self, ttl_secs: u32) -> Self {
+        self.ttl_secs = ttl_secs;
+        self.expires_at = self.timestamp + Duration::seconds(ttl_secs.into());
+        self
+    }
+
+    /// Marks the pin as answered at `now`, restarting its lifetime, though
+    /// never past `max_lifetime_secs` after it was created.
+    pub fn touch(&mut self, now: DateTime<Utc>, max_lifetime_secs: u32) {
+        self.timestamp = now;
+        self.expires_at = (now + Duration::seconds(self.ttl_secs.into()))
+            .min(self.created_at + Duration::seconds(max_lifetime_secs.into()));
+    }
+
+    /// Cancels the pin at `now`, dropping any result it was holding.
+    pub fn revoke(&mut self, now: DateTime<Utc>) {
+        self.revoked_at = Some(now);
+        self.result = None;
+    }
+
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    /// Marks the result as handed to the receiver at `now`.
+    pub fn consume(&mut self, now: DateTime<Utc>) {
+        self.consumed_at = Some(now);
+        self.result = None;
+    }
+
+    /// Drops the result of a pin that expired before anyone took it, so it is
+    /// not kept for as long as the tombstone. Returns whether there was one.
+    pub fn drop_expired_result(&mut self, now: DateTime<Utc>) -> bool {
+        if self.state(now) != PinState::Expired || self.result.is_none() {
+            return false;
+        }
+        self.result = None;
+        true
+    }
+
+    pub fn state(&self, now: DateTime<Utc>) -> PinState {
+        if self.revoked_at.is_some() {
+            PinState::Revoked
+        } else if self.consumed_at.is_some() {
+            PinState::Consumed
+        } else if self.expires_at <= now {
+            PinState::Expired
+        } else if self.result.is_some() {
+            PinState::Fulfilled
+        } else {
+            PinState::AwaitingData
+        }
+    }
+
+    /// Whether the pin can still be answered, renewed or taken at `now`.
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        matches!(
+            self.state(now),
+            PinState::AwaitingData | PinState::Fulfilled
+        )
+    }
+
+    /// When the pin stopped being live, if it has.
+    pub fn ended_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self.state(now) {
+            PinState::Revoked => self.revoked_at,
+            PinState::Consumed => self.consumed_at,
+            PinState::Expired => Some(self.expires_at),
+            PinState::AwaitingData | PinState::Fulfilled => None,
+        }
+    }
+
+    /// When the record can be dropped: a tombstone's lifetime after the pin
+    /// ended, or would end if nothing else happens to it.
+    pub fn purge_at(&self) -> DateTime<Utc> {
+        let ends_at = self
+            .revoked_at
+            .or(self.consumed_at)
+            .unwrap_or(self.expires_at);
+        ends_at + Duration::seconds(self.tombstone_secs.into())
+    }
+}
+
+/// Runs blocking backend I/O from a request handler. On a multi-threaded
+/// runtime the worker first hands its other tasks to another thread, so one slow
+/// database call does not stall every connection scheduled behind it.
+#[cfg(any(feature = "sqlite", feature = "redis"))]
+pub(crate) fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+// Helper function to create consistent keys
+pub fn create_key(namespace: &str, pin: &str) -> String {
+    format!("{}:{}", namespace, pin)
+}
+
+/// `PinStore::take_if_populated` in terms of `update`, for backends without a
+/// dedicated way to take a pin.
+pub(crate) fn take_by_update<S: PinStore + ?Sized>(
+    store: &S,
+    namespace: &str,
+    pin: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<PinItem>> {
+    let mut seen = None;
+    let mut ended = false;
+    let taken = store.update(namespace, pin, &mut |item| {
+        ended = !item.is_live(now);
+        if !ended {
+            item.reads += 1;
+            item.last_read_at = Some(now);
+        }
+        seen = Some(item.clone());
+        if item.result.is_some() {
+            item.consume(now);
+        }
+        !ended
+    })?;
+    // A retried update can find the pin gone after an earlier attempt saw it.
+    Ok(seen.filter(|_| taken.is_some() || ended))
+}
+
+/// Storage for pins, keyed by namespace and pin.
+///
+/// Every operation is atomic with respect to the others, so handlers never
+/// need a separate existence check before writing.
+pub trait PinStore: Send + Sync {
+    /// Inserts `item` unless its pin is already taken in `namespace`.
+    /// Returns whether it was inserted.
+    fn create_if_absent(&self, namespace: &str, item: PinItem) -> anyhow::Result<bool>;
+
+    /// Runs `update` against the stored pin and keeps the changes if it returns
+    /// true. Returns the updated item, or `None` if the pin does not exist or
+    /// the update was declined. `update` may run more than once if the backend
+    /// retries on contention, so it must not have side effects of its own.
+    fn update(
+        &self,
+        namespace: &str,
+        pin: &str,
+        update: &mut dyn FnMut(&mut PinItem) -> bool,
+    ) -> anyhow::Result<Option<PinItem>>;
+
+    /// Counts a read of a live pin and, if it has a result, hands the result
+    /// over and leaves the pin consumed. Returns the pin as the reader saw it,
+    /// result included; pins that have already ended are returned unchanged.
+    fn take_if_populated(
+        &self,
+        namespace: &str,
+        pin: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<PinItem>> {
+        take_by_update(self, namespace, pin, now)
+    }
+
+    /// Removes every record whose purge time has passed by `now`, returning
+    /// the removed `(namespace, pin)` pairs.
+    fn expire_all_due(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<(String, String)>>;
+
+    /// Removes the record if its purge time has passed by `now`. Returns
+    /// whether it was removed.
+    fn expire_if_due(&self, namespace: &str, pin: &str, now: DateTime<Utc>)
+        -> anyhow::Result<bool>;
+
+    /// Every stored pin with its purge time, so expiry can be rescheduled after a restart.
+    fn deadlines(&self) -> anyhow::Result<Vec<(String, String, DateTime<Utc>)>>;
+
+    /// Whether the backend drops stale pins by itself, making expiry scheduling unnecessary.
+    fn expires_natively(&self) -> bool {
+        false
+    }
+
+    /// Whether other replicas may change pins in this store behind this process's back.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Looks up a pin without changing it.
+    fn get(&self, namespace: &str, pin: &str) -> anyhow::Result<Option<PinItem>>;
+
+    /// Makes recent writes durable if the backend buffers them. Called every second.
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Persists the whole store if the backend keeps it in memory. Called on a
+    /// timer and at shutdown.
+    fn checkpoint(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreBackend {
+    Memory,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    #[cfg(feature = "redis")]
+    Redis,
+}
+
+impl FromStr for StoreBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "memory" => Ok(StoreBackend::Memory),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(StoreBackend::Sqlite),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => anyhow::bail!("This build does not include the sqlite feature"),
+            #[cfg(feature = "redis")]
+            "redis" => Ok(StoreBackend::Redis),
+            #[cfg(not(feature = "redis"))]
+            "redis" => anyhow::bail!("This build does not include the redis feature"),
+            _ => anyhow::bail!("Unknown store backend {:?}", s),
+        }
+    }
+}
+
+/// Opens the backend selected in `config`, restoring any persisted pins and
+/// dropping those that went stale while the service was down.
+pub fn open(config: &Config, clock: Arc<dyn Clock>) -> anyhow::Result<Arc<dyn PinStore>> {
+    let store = open_backend(config, &clock)?;
+    let expired = store.expire_all_due(clock.now())?;
+    if !expired.is_empty() {
+        info!("Dropped {} pins that expired while stopped", expired.len());
+    }
+    Ok(store)
+}
+
+#[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+fn open_backend(config: &Config, clock: &Arc<dyn Clock>) -> anyhow::Result<Arc<dyn PinStore>> {
+    match config.store_backend {
+        StoreBackend::Memory => {
+            let store = match &config.snapshot_path {
+                Some(snapshot_path) => {
+                    MemoryStore::open(snapshot_path, config.wal_path.as_deref(), config.wal_fsync)
+                        .context("Could not restore the in-memory store")?
+                }
+                None => MemoryStore::new(),
+            };
+            Ok(Arc::new(store))
+        }
+        #[cfg(feature = "sqlite")]
+        StoreBackend::Sqlite => Ok(Arc::new(SqliteStore::open(&config.sqlite_path)?)),
+        #[cfg(feature = "redis")]
+        StoreBackend::Redis => Ok(Arc::new(RedisStore::open(
+            &config.redis_url,
+            &config.redis_key_prefix,
+            clock.clone(),
+        )?)),
+    }
+}
+
+/// Behaviour every backend must share. Each backend's tests call these
+/// against a fresh store.
+#[cfg(test)]
+pub(crate) mod conformance {
+    use super::*;
+    use crate::seal::{Keyring, Plaintext};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::OnceLock;
+
+    fn data(value: &str) -> Plaintext {
+        HashMap::from([("value".to_string(), json!(value))]).into()
+    }
+
+    fn keyring() -> &'static Keyring {
+        static KEYRING: OnceLock<Keyring> = OnceLock::new();
+        KEYRING.get_or_init(Keyring::generate)
+    }
+
+    fn sealed(namespace: &str, pin: &str, value: &str) -> SealedResult {
+        keyring().seal(namespace, pin, &data(value)).unwrap()
+    }
+
+    /// The result a pin in `namespace` holds, opened.
+    fn opened(namespace: &str, item: &PinItem) -> Option<Plaintext> {
+        let result = item.result.as_ref()?;
+        Some(keyring().open(namespace, &item.pin, result).unwrap())
+    }
+
+    fn fill(store: &dyn PinStore, namespace: &str, pin: &str, value: &str) -> Option<PinItem> {
+        let result = sealed(namespace, pin, value);
+        store
+            .update(namespace, pin, &mut |item| {
+                item.result = Some(result.clone());
+                true
+            })
+            .unwrap()
+    }
+
+    pub fn create_if_absent(store: &dyn PinStore) {
+        assert!(store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap());
+        assert!(!store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap());
+        // The same pin is free in another namespace.
+        assert!(store
+            .create_if_absent("other", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap());
+
+        let item = store.get("ns", "ABCD").unwrap().unwrap();
+        assert_eq!(item.pin, "ABCD");
+        assert!(item.result.is_none());
+        assert!(store.get("ns", "WXYZ").unwrap().is_none());
+    }
+
+    pub fn create_does_not_overwrite(store: &dyn PinStore) {
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        fill(store, "ns", "ABCD", "kept");
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+
+        let item = store.get("ns", "ABCD").unwrap().unwrap();
+        assert_eq!(opened("ns", &item), Some(data("kept")));
+    }
+
+    pub fn update_conditions(store: &dyn PinStore) {
+        assert!(fill(store, "ns", "ABCD", "missing").is_none());
+        assert!(store.get("ns", "ABCD").unwrap().is_none());
+
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        let declined = store.update("ns", "ABCD", &mut |item| {
+            item.result = Some(sealed("ns", "ABCD", "declined"));
+            false
+        });
+        assert!(declined.unwrap().is_none());
+        assert!(store.get("ns", "ABCD").unwrap().unwrap().result.is_none());
+
+        let updated = fill(store, "ns", "ABCD", "accepted").unwrap();
+        assert_eq!(opened("ns", &updated), Some(data("accepted")));
+        assert_eq!(store.get("ns", "ABCD").unwrap().unwrap(), updated);
+    }
+
+    pub fn take_if_populated(store: &dyn PinStore) {
+        let now = Utc::now();
+        assert!(store
+            .take_if_populated("ns", "ABCD", now)
+            .unwrap()
+            .is_none());
+
+        // A tombstone keeps the consumed pin around long enough to look at.
+        let mut item = PinItem::new("ABCD".to_string(), None, now);
+        item.tombstone_secs = 60;
+        store.create_if_absent("ns", item).unwrap();
+        let empty = store.take_if_populated("ns", "ABCD", now).unwrap().unwrap();
+        assert!(empty.result.is_none());
+        assert_eq!(empty.reads, 1);
+        assert_eq!(empty.last_read_at, Some(now));
+        assert_eq!(
+            store.get("ns", "ABCD").unwrap().unwrap().state(now),
+            PinState::AwaitingData
+        );
+
+        fill(store, "ns", "ABCD", "payload");
+        let taken = store.take_if_populated("ns", "ABCD", now).unwrap().unwrap();
+        assert_eq!(opened("ns", &taken), Some(data("payload")));
+        assert_eq!(taken.reads, 2);
+
+        // The consumed pin stays behind without its result.
+        let left = store.get("ns", "ABCD").unwrap().unwrap();
+        assert_eq!(left.state(now), PinState::Consumed);
+        assert!(left.result.is_none());
+        let again = store.take_if_populated("ns", "ABCD", now).unwrap().unwrap();
+        assert_eq!(again, left);
+    }
+
+    pub fn take_is_exclusive(store: Arc<dyn PinStore>) {
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        fill(&*store, "ns", "ABCD", "once");
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    store.take_if_populated("ns", "ABCD", Utc::now()).unwrap()
+                })
+            })
+            .collect();
+        let winners = handles
+            .into_iter()
+            .filter_map(|handle| handle.join().unwrap())
+            .filter(|item| item.result.is_some())
+            .count();
+        assert_eq!(winners, 1);
+    }
+
+    pub fn expire_all_due(store: &dyn PinStore) {
+        let now = Utc::now();
+        let old = PinItem::new("OLD1".to_string(), None, now - Duration::minutes(11)).with_ttl(600);
+        store.create_if_absent("ns", old).unwrap();
+        let short = PinItem::new("SHRT".to_string(), None, now - Duration::minutes(2)).with_ttl(60);
+        store.create_if_absent("ns", short).unwrap();
+        let long =
+            PinItem::new("LONG".to_string(), None, now - Duration::minutes(11)).with_ttl(3600);
+        store.create_if_absent("ns", long).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("NEW1".to_string(), None, now))
+            .unwrap();
+
+        // Give backends with native expiry a moment to drop the stale pins.
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let mut expired = store.expire_all_due(now).unwrap();
+        expired.sort();
+        // Backends with native expiry will already have dropped them themselves.
+        let due = vec![
+            ("ns".to_string(), "OLD1".to_string()),
+            ("ns".to_string(), "SHRT".to_string()),
+        ];
+        assert!(expired.is_empty() || expired == due);
+        assert!(store.get("ns", "OLD1").unwrap().is_none());
+        assert!(store.get("ns", "SHRT").unwrap().is_none());
+        assert!(store.get("ns", "LONG").unwrap().is_some());
+        assert!(store.get("ns", "NEW1").unwrap().is_some());
+        assert!(store.expire_all_due(now).unwrap().is_empty());
+    }
+
+    pub fn expire_if_due(store: &dyn PinStore) {
+        let now = Utc::now();
+        let old = PinItem::new("OLD1".to_string(), None, now - Duration::minutes(11));
+        let old_deadline = old.expires_at;
+        store.create_if_absent("ns", old).unwrap();
+        let fresh = PinItem::new("NEW1".to_string(), None, Utc::now());
+        let fresh_deadline = fresh.expires_at;
+        store.create_if_absent("ns", fresh).unwrap();
+
+        if store.expires_natively() {
+            // Nothing to schedule; the backend has already dropped the stale pin.
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(store.deadlines().unwrap().is_empty());
+            assert!(store.get("ns", "OLD1").unwrap().is_none());
+            return;
+        }
+
+        let mut deadlines = store.deadlines().unwrap();
+        deadlines.sort();
+        assert_eq!(
+            deadlines,
+            vec![
+                ("ns".to_string(), "NEW1".to_string(), fresh_deadline),
+                ("ns".to_string(), "OLD1".to_string(), old_deadline),
+            ]
+        );
+        assert!(store.expire_if_due("ns", "OLD1", now).unwrap());
+        assert!(store.get("ns", "OLD1").unwrap().is_none());
+        assert!(!store.expire_if_due("ns", "OLD1", now).unwrap());
+        assert!(!store.expire_if_due("ns", "NEW1", now).unwrap());
+        assert!(store.get("ns", "NEW1").unwrap().is_some());
+        assert!(store.expire_if_due("ns", "NEW1", fresh_deadline).unwrap());
+        assert!(store.get("ns", "NEW1").unwrap().is_none());
+    }
+
+    pub fn tombstones_outlive_pins(store: &dyn PinStore) {
+        let now = Utc::now();
+        let mut expired =
+            PinItem::new("GONE".to_string(), None, now - Duration::minutes(11)).with_ttl(600);
+        expired.tombstone_secs = 120;
+        store.create_if_absent("ns", expired).unwrap();
+        let mut consumed = PinItem::new("USED".to_string(), None, now);
+        consumed.tombstone_secs = 120;
+        store.create_if_absent("ns", consumed).unwrap();
+        fill(store, "ns", "USED", "payload");
+        store.take_if_populated("ns", "USED", now).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store.expire_all_due(now).unwrap();
+        let gone = store.get("ns", "GONE").unwrap().unwrap();
+        assert_eq!(gone.state(now), PinState::Expired);
+        assert_eq!(gone.ended_at(now), Some(now - Duration::minutes(1)));
+        let used = store.get("ns", "USED").unwrap().unwrap();
+        assert_eq!(used.state(now), PinState::Consumed);
+        assert_eq!(used.purge_at(), now + Duration::minutes(2));
+
+        if !store.expires_natively() {
+            store.expire_all_due(now + Duration::minutes(3)).unwrap();
+            assert!(store.get("ns", "GONE").unwrap().is_none());
+            assert!(store.get("ns", "USED").unwrap().is_none());
+        }
+    }
+
+    /// Runs every conformance check, each against a fresh store from `new_store`.
+    pub fn run_all(new_store: impl Fn() -> Arc<dyn PinStore>) {
+        create_if_absent(&*new_store());
+        create_does_not_overwrite(&*new_store());
+        update_conditions(&*new_store());
+        take_if_populated(&*new_store());
+        take_is_exclusive(new_store());
+        expire_all_due(&*new_store());
+        expire_if_due(&*new_store());
+        tombstones_outlive_pins(&*new_store());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+
+    #[test]
+    fn test_open_drops_stale_pins() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            snapshot_path: Some(dir.path().join("pins.json")),
+            ..Config::default()
+        };
+
+        let store = open(&config, Arc::new(SystemClock)).unwrap();
+        let old = PinItem::new("OLD1".to_string(), None, Utc::now() - Duration::minutes(11));
+        store.create_if_absent("ns", old).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("NEW1".to_string(), None, Utc::now()))
+            .unwrap();
+        store.checkpoint().unwrap();
+        drop(store);
+
+        let store = open(&config, Arc::new(SystemClock)).unwrap();
+        assert!(store.get("ns", "OLD1").unwrap().is_none());
+        assert!(store.get("ns", "NEW1").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_backend_parsing() {
+        assert_eq!(
+            "memory".parse::<StoreBackend>().unwrap(),
+            StoreBackend::Memory
+        );
+        assert!("floppy".parse::<StoreBackend>().is_err());
+    }
+}
@@ -0,0 +1,433 @@
+use super::{blocking, create_key, PinItem, PinStore};
+use crate::clock::Clock;
+use anyhow::Context;
+use chrono::prelude::{DateTime, Utc};
+use chrono::SecondsFormat;
+use redis::{Commands, Connection, Script};
+use std::sync::{Arc, Mutex};
+
+/// How many times a compare-and-swap is retried when another replica changes
+/// the pin between our read and our write.
+const MAX_CAS_ATTEMPTS: usize = 16;
+
+/// Connections kept open between requests. More are opened when needed, and
+/// closed again once this many are idle.
+const MAX_IDLE_CONNECTIONS: usize = 16;
+
+/// Replace the value only if nobody has changed it since we read it.
+const CAS_SET_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+    return 1
+end
+return 0
+";
+
+/// Counts a read of a live pin and hands over its result in one step, so the
+/// busiest path never loses a compare-and-swap to polls on other replicas.
+/// Returns the record as it was found and whether the read was counted, or nil.
+const TAKE_SCRIPT: &str = r"
+local raw = redis.call('GET', KEYS[1])
+if not raw then
+    return false
+end
+local function absent(value)
+    return value == nil or value == cjson.null
+end
+-- Timestamps differ only in how many fraction digits they carry, so pad those
+-- to nanoseconds and compare them as text.
+local function instant(timestamp)
+    local whole, fraction = string.match(timestamp, '^([%d%-]+T[%d:]+)%.?(%d*)')
+    return whole .. fraction .. string.rep('0', 9 - #fraction)
+end
+local item = cjson.decode(raw)
+if not absent(item.revoked_at) or not absent(item.consumed_at)
+    or instant(item.expires_at) <= instant(ARGV[1]) then
+    return {raw, 0}
+end
+item.reads = (item.reads or 0) + 1
+item.last_read_at = ARGV[1]
+if absent(item.result) then
+    redis.call('SET', KEYS[1], cjson.encode(item), 'KEEPTTL')
+else
+    item.result = cjson.null
+    item.consumed_at = ARGV[1]
+    local tombstone = math.max(1, (item.tombstone_secs or 0) * 1000)
+    redis.call('SET', KEYS[1], cjson.encode(item), 'PX', tombstone)
+end
+return {raw, 1}
+";
+
+/// Backend for running several replicas against one Redis. Pins expire through
+/// native key TTLs, so no replica needs to sweep.
+pub struct RedisStore {
+    client: redis::Client,
+    idle: Mutex<Vec<Connection>>,
+    key_prefix: String,
+    cas_set: Script,
+    take: Script,
+    /// Key TTLs are counted from this clock's now.
+    clock: Arc<dyn Clock>,
+}
+
+impl RedisStore {
+    pub fn open(url: &str, key_prefix: &str, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url).context("Invalid REDIS_URL")?;
+        let store = RedisStore {
+            client,
+            idle: Mutex::new(Vec::new()),
+            key_prefix: key_prefix.to_string(),
+            cas_set: Script::new(CAS_SET_SCRIPT),
+            take: Script::new(TAKE_SCRIPT),
+            clock,
+        };
+        // Fail at startup rather than on the first request.
+        store.with_conn(|conn| redis::cmd("PING").query::<String>(conn))?;
+        Ok(store)
+    }
+
+    fn key(&self, namespace: &str, pin: &str) -> String {
+        format!("{}{}", self.key_prefix, create_key(namespace, pin))
+    }
+
+    /// Runs `f` on an idle connection, or a new one if none is idle, off the
+    /// async workers. Connections a command left broken are dropped.
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> redis::RedisResult<T>,
+    ) -> anyhow::Result<T> {
+        let idle = self.lock_idle()?.pop();
+        blocking(|| {
+            let mut conn = match idle {
+                Some(conn) => conn,
+                None => self
+                    .client
+                    .get_connection()
+                    .context("Could not connect to Redis")?,
+            };
+            let result = f(&mut conn);
+            let broken = result.as_ref().is_err_and(|e| {
+                e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal()
+            });
+            if !broken {
+                let mut idle = self.lock_idle()?;
+                if idle.len() < MAX_IDLE_CONNECTIONS {
+                    idle.push(conn);
+                }
+            }
+            Ok(result?)
+        })
+    }
+
+    fn lock_idle(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Vec<Connection>>> {
+        self.idle
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Redis connection lock poisoned"))
+    }
+
+    fn get_raw(&self, key: &str) -> anyhow::Result<Option<String>> {
+        self.with_conn(|conn| conn.get(key))
+    }
+
+    /// Milliseconds until `item` can be purged, never less than one so Redis accepts it.
+    fn ttl_millis(&self, item: &PinItem) -> i64 {
+        (item.purge_at() - self.clock.now())
+            .num_milliseconds()
+            .max(1)
+    }
+}
+
+fn decode(raw: &str) -> anyhow::Result<PinItem> {
+    serde_json::from_str(raw).context("Corrupt pin record")
+}
+
+impl PinStore for RedisStore {
+    fn create_if_absent(&self, namespace: &str, item: PinItem) -> anyhow::Result<bool> {
+        let key = self.key(namespace, &item.pin);
+        let value = serde_json::to_string(&item)?;
+        let created: Option<String> = self.with_conn(|conn| {
+            redis::cmd("SET")
+                .arg(&key)
+                .arg(value)
+                .arg("NX")
+                .arg("PX")
+                .arg(self.ttl_millis(&item))
+                .query(conn)
+        })?;
+        Ok(created.is_some())
+    }
+
+    fn update(
+        &self,
+        namespace: &str,
+        pin: &str,
+        update: &mut dyn FnMut(&mut PinItem) -> bool,
+    ) -> anyhow::Result<Option<PinItem>> {
+        let key = self.key(namespace, pin);
+        for _ in 0..MAX_CAS_ATTEMPTS {
+            let Some(raw) = self.get_raw(&key)? else {
+                return Ok(None);
+            };
+            let mut item = decode(&raw)?;
+            if !update(&mut item) {
+                return Ok(None);
+            }
+            let value = serde_json::to_string(&item)?;
+            let swapped: i32 = self.with_conn(|conn| {
+                self.cas_set
+                    .key(&key)
+                    .arg(&raw)
+                    .arg(value)
+                    .arg(self.ttl_millis(&item))
+                    .invoke(conn)
+            })?;
+            if swapped == 1 {
+                return Ok(Some(item));
+            }
+        }
+        anyhow::bail!("Gave up updating {} after repeated conflicts", key)
+    }
+
+    fn take_if_populated(
+        &self,
+        namespace: &str,
+        pin: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<PinItem>> {
+        let key = self.key(namespace, pin);
+        let now_text = now.to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let found: Option<(String, i32)> =
+            self.with_conn(|conn| self.take.key(&key).arg(now_text).invoke(conn))?;
+        let Some((raw, counted)) = found else {
+            return Ok(None);
+        };
+        let mut item = decode(&raw)?;
+        if counted == 1 {
+            item.reads += 1;
+            item.last_read_at = Some(now);
+        } else if item.is_live(now) {
+            anyhow::bail!("The take script passed over live pin {}", key);
+        }
+        Ok(Some(item))
+    }
+
+    /// Redis expires keys itself, so there is never anything left to sweep.
+    fn expire_all_due(&self, _now: DateTime<Utc>) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(Vec::new())
+    }
+
+    /// Redis expires keys itself at their deadline.
+    fn expire_if_due(
+        &self,
+        _namespace: &str,
+        _pin: &str,
+        _now: DateTime<Utc>,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn deadlines(&self) -> anyhow::Result<Vec<(String, String, DateTime<Utc>)>> {
+        Ok(Vec::new())
+    }
+
+    fn expires_natively(&self) -> bool {
+        true
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    fn get(&self, namespace: &str, pin: &str) -> anyhow::Result<Option<PinItem>> {
+        self.get_raw(&self.key(namespace, pin))?
+            .map(|raw| decode(&raw))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::store::conformance;
+    use chrono::Duration;
+    use rand::distr::Alphanumeric;
+    use rand::{rng, Rng};
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Child, Command, Stdio};
+    use std::time::Instant;
+
+    /// The Redis to test against: `REDIS_URL` if set, otherwise a
+    /// `redis-server` launched on a spare port.
+    struct TestRedis {
+        url: String,
+        server: Option<Child>,
+    }
+
+    impl TestRedis {
+        /// `None` when there is no Redis to test against, so the test can skip.
+        /// Under CI that is a failure instead.
+        fn start() -> Option<Self> {
+            if let Ok(url) = std::env::var("REDIS_URL") {
+                return Some(TestRedis { url, server: None });
+            }
+            if let Some(redis) = Self::launch_redis_server() {
+                return Some(redis);
+            }
+            if std::env::var_os("CI").is_some() {
+                panic!("The Redis tests need REDIS_URL or a redis-server on PATH");
+            }
+            eprintln!("skipping: set REDIS_URL or install redis-server to run the Redis tests");
+            None
+        }
+
+        fn launch_redis_server() -> Option<Self> {
+            let port = TcpListener::bind("127.0.0.1:0")
+                .ok()?
+                .local_addr()
+                .ok()?
+                .port();
+            let mut server = Command::new("redis-server")
+                .args([
+                    "--port",
+                    &port.to_string(),
+                    "--save",
+                    "",
+                    "--appendonly",
+                    "no",
+                ])
+                .stdout(Stdio::null())
+                .spawn()
+                .ok()?;
+            let deadline = Instant::now() + std::time::Duration::from_secs(5);
+            while TcpStream::connect(("127.0.0.1", port)).is_err() {
+                if Instant::now() > deadline {
+                    server.kill().ok();
+                    server.wait().ok();
+                    return None;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            Some(TestRedis {
+                url: format!("redis://127.0.0.1:{}/", port),
+                server: Some(server),
+            })
+        }
+
+        fn open(&self, prefix: &str) -> RedisStore {
+            RedisStore::open(&self.url, prefix, Arc::new(SystemClock)).unwrap()
+        }
+    }
+
+    impl Drop for TestRedis {
+        fn drop(&mut self) {
+            if let Some(server) = &mut self.server {
+                server.kill().ok();
+                server.wait().ok();
+            }
+        }
+    }
+
+    fn random_prefix() -> String {
+        let suffix: String = rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        format!("configgymajiggy-test-{}:", suffix)
+    }
+
+    #[test]
+    fn test_conformance() {
+        let Some(redis) = TestRedis::start() else {
+            return;
+        };
+        conformance::run_all(|| Arc::new(redis.open(&random_prefix())));
+    }
+
+    #[test]
+    fn test_pins_get_native_ttl() {
+        let Some(redis) = TestRedis::start() else {
+            return;
+        };
+        let prefix = random_prefix();
+        let store = redis.open(&prefix);
+        let item = PinItem::new("ABCD".to_string(), None, Utc::now()).with_ttl(120);
+        store.create_if_absent("ns", item).unwrap();
+
+        let mut conn = redis::Client::open(redis.url.as_str())
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let ttl: i64 = redis::cmd("PTTL")
+            .arg(format!("{}ns:ABCD", prefix))
+            .query(&mut conn)
+            .unwrap();
+        assert!(ttl > 110_000 && ttl <= 120_000, "ttl was {}", ttl);
+    }
+
+    #[test]
+    fn test_pins_expire_without_sweep() {
+        let Some(redis) = TestRedis::start() else {
+            return;
+        };
+        let store = redis.open(&random_prefix());
+        let item = PinItem::new(
+            "ABCD".to_string(),
+            None,
+            Utc::now() - Duration::milliseconds(800),
+        )
+        .with_ttl(1);
+        store.create_if_absent("ns", item).unwrap();
+        assert!(store.get("ns", "ABCD").unwrap().is_some());
+
+        std::thread::sleep(std::time::Duration::from_millis(400));
+        assert!(store.get("ns", "ABCD").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reads_are_counted_under_contention() {
+        let Some(redis) = TestRedis::start() else {
+            return;
+        };
+        let prefix = random_prefix();
+        let replicas: Vec<Arc<RedisStore>> =
+            (0..2).map(|_| Arc::new(redis.open(&prefix))).collect();
+        replicas[0]
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                let store = replicas[i % 2].clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        store.take_if_populated("ns", "ABCD", Utc::now()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(replicas[1].get("ns", "ABCD").unwrap().unwrap().reads, 320);
+    }
+
+    #[test]
+    fn test_replicas_share_pins() {
+        let Some(redis) = TestRedis::start() else {
+            return;
+        };
+        let prefix = random_prefix();
+        let replica_a = redis.open(&prefix);
+        let replica_b = redis.open(&prefix);
+
+        replica_a
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        assert!(!replica_b
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap());
+        assert!(replica_b.get("ns", "ABCD").unwrap().is_some());
+    }
+}
@@ -0,0 +1,176 @@
+use super::{create_key, PinItem};
+use anyhow::Context;
+use chrono::prelude::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Point-in-time copy of every pin in the store.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub namespace: String,
+    #[serde(flatten)]
+    pub item: PinItem,
+}
+
+impl Snapshot {
+    /// Builds a snapshot from `(key, item)` pairs as stored in the map.
+    pub fn from_items(items: impl IntoIterator<Item = (String, PinItem)>) -> Self {
+        let entries = items
+            .into_iter()
+            .filter_map(|(key, item)| {
+                // Pins are alphanumeric, so the last ':' always separates the namespace.
+                let (namespace, _) = key.rsplit_once(':')?;
+                Some(SnapshotEntry {
+                    namespace: namespace.to_string(),
+                    item,
+                })
+            })
+            .collect();
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: Utc::now(),
+            entries,
+        }
+    }
+
+    /// Turns the snapshot back into `(key, item)` pairs.
+    pub fn into_items(self) -> impl Iterator<Item = (String, PinItem)> {
+        self.entries
+            .into_iter()
+            .map(|entry| (create_key(&entry.namespace, &entry.item.pin), entry.item))
+    }
+
+    /// Writes the snapshot next to `path` and renames it into place, so a crash
+    /// mid-write never leaves a truncated snapshot behind.
+    pub fn write_atomic(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp_path = tmp_path_for(path);
+        {
+            let file = File::create(&tmp_path)
+                .with_context(|| format!("Could not create {}", tmp_path.display()))?;
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer(&mut writer, self)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not move snapshot into {}", path.display()))?;
+        sync_parent_dir(path)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot, returning `None` if none has been written yet.
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Could not open {}", path.display())),
+        };
+        let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Could not parse snapshot {}", path.display()))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            anyhow::bail!("Unsupported snapshot version {}", snapshot.version);
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seal::Keyring;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.json");
+
+        let mut data = HashMap::new();
+        data.insert("wifi".to_string(), json!("hunter2"));
+        let sealed = Keyring::generate()
+            .seal("ns:with:colons", "ABCD", &data.into())
+            .unwrap();
+        let items = vec![
+            (
+                create_key("ns:with:colons", "ABCD"),
+                PinItem::new("ABCD".to_string(), Some(sealed), Utc::now()),
+            ),
+            (
+                create_key("other", "WXYZ"),
+                PinItem::new("WXYZ".to_string(), None, Utc::now()),
+            ),
+        ];
+
+        let snapshot = Snapshot::from_items(items.clone());
+        assert_eq!(snapshot.entries[0].namespace, "ns:with:colons");
+        snapshot.write_atomic(&path).unwrap();
+        assert!(!tmp_path_for(&path).exists());
+        assert!(!fs::read_to_string(&path).unwrap().contains("hunter2"));
+
+        let restored = Snapshot::read(&path).unwrap().unwrap();
+        assert_eq!(restored.into_items().collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn test_read_snapshot_with_incomplete_pins_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.json");
+        fs::write(
+            &path,
+            r#"{"version": 1, "taken_at": "2024-01-01T00:00:00Z", "entries": [
+                {"namespace": "ns", "pin": "ABCD", "timestamp": "2024-01-01T00:00:00Z", "result": null}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert!(Snapshot::read(&path).is_err());
+    }
+
+    #[test]
+    fn test_read_missing_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Snapshot::read(&dir.path().join("nope.json"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_corrupt_snapshot_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.json");
+        fs::write(&path, b"{\"version\": 1, \"entr").unwrap();
+
+        assert!(Snapshot::read(&path).is_err());
+    }
+}
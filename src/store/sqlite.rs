@@ -0,0 +1,221 @@
+use super::{blocking, PinItem, PinStore};
+use anyhow::Context;
+use chrono::prelude::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS pins (
+        namespace TEXT NOT NULL,
+        pin TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        purge_at INTEGER NOT NULL,
+        item TEXT NOT NULL,
+        PRIMARY KEY (namespace, pin)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS pins_by_purge ON pins (purge_at);
+";
+
+/// Single-node backend that survives restarts without snapshots. Each
+/// operation runs in its own transaction, so check-then-write races between
+/// concurrent requests cannot happen.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Could not open SQLite database {}", path.display()))?;
+        // WAL journaling lets readers in other processes (e.g. the sqlite3 CLI) coexist with us.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Self::from_connection(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> anyhow::Result<Self> {
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Runs `f` on the connection, off the async workers since SQLite blocks.
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        blocking(|| {
+            let mut conn: MutexGuard<'_, Connection> = self
+                .conn
+                .lock()
+                .map_err(|_| anyhow::anyhow!("SQLite connection lock poisoned"))?;
+            f(&mut conn)
+        })
+    }
+}
+
+fn select_item(conn: &Connection, namespace: &str, pin: &str) -> anyhow::Result<Option<PinItem>> {
+    let item: Option<String> = conn
+        .query_row(
+            "SELECT item FROM pins WHERE namespace = ?1 AND pin = ?2",
+            params![namespace, pin],
+            |row| row.get(0),
+        )
+        .optional()?;
+    item.map(|item| serde_json::from_str(&item).context("Corrupt pin record"))
+        .transpose()
+}
+
+impl PinStore for SqliteStore {
+    fn create_if_absent(&self, namespace: &str, item: PinItem) -> anyhow::Result<bool> {
+        self.with_conn(|conn| {
+            let inserted = conn.execute(
+                "INSERT INTO pins (namespace, pin, timestamp, purge_at, item)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (namespace, pin) DO NOTHING",
+                params![
+                    namespace,
+                    item.pin,
+                    item.timestamp.timestamp_millis(),
+                    item.purge_at().timestamp_millis(),
+                    serde_json::to_string(&item)?
+                ],
+            )?;
+            Ok(inserted == 1)
+        })
+    }
+
+    fn update(
+        &self,
+        namespace: &str,
+        pin: &str,
+        update: &mut dyn FnMut(&mut PinItem) -> bool,
+    ) -> anyhow::Result<Option<PinItem>> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let Some(mut item) = select_item(&tx, namespace, pin)? else {
+                return Ok(None);
+            };
+            if !update(&mut item) {
+                return Ok(None);
+            }
+            tx.execute(
+                "UPDATE pins SET timestamp = ?3, purge_at = ?4, item = ?5
+             WHERE namespace = ?1 AND pin = ?2",
+                params![
+                    namespace,
+                    pin,
+                    item.timestamp.timestamp_millis(),
+                    item.purge_at().timestamp_millis(),
+                    serde_json::to_string(&item)?
+                ],
+            )?;
+            tx.commit()?;
+            Ok(Some(item))
+        })
+    }
+
+    fn expire_all_due(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<(String, String)>> {
+        self.with_conn(|conn| {
+            let mut statement =
+                conn.prepare("DELETE FROM pins WHERE purge_at <= ?1 RETURNING namespace, pin")?;
+            let expired = statement
+                .query_map(params![now.timestamp_millis()], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(expired)
+        })
+    }
+
+    fn expire_if_due(
+        &self,
+        namespace: &str,
+        pin: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<bool> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let Some(item) = select_item(&tx, namespace, pin)? else {
+                return Ok(false);
+            };
+            if item.purge_at() > now {
+                return Ok(false);
+            }
+            tx.execute(
+                "DELETE FROM pins WHERE namespace = ?1 AND pin = ?2",
+                params![namespace, pin],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+    }
+
+    fn deadlines(&self) -> anyhow::Result<Vec<(String, String, DateTime<Utc>)>> {
+        let rows = self.with_conn(|conn| {
+            let mut statement = conn.prepare("SELECT namespace, item FROM pins")?;
+            let rows = statement
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })?;
+        rows.into_iter()
+            .map(|(namespace, item)| {
+                let item: PinItem = serde_json::from_str(&item).context("Corrupt pin record")?;
+                Ok((namespace, item.pin.clone(), item.purge_at()))
+            })
+            .collect()
+    }
+
+    fn get(&self, namespace: &str, pin: &str) -> anyhow::Result<Option<PinItem>> {
+        self.with_conn(|conn| select_item(conn, namespace, pin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::conformance;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(|| Arc::new(SqliteStore::open_in_memory().unwrap()));
+    }
+
+    #[test]
+    fn test_conformance_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let counter = std::sync::atomic::AtomicUsize::new(0);
+        conformance::run_all(|| {
+            let n = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let path = dir.path().join(format!("pins-{}.sqlite3", n));
+            Arc::new(SqliteStore::open(&path).unwrap())
+        });
+    }
+
+    #[test]
+    fn test_pins_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.sqlite3");
+
+        let store = SqliteStore::open(&path).unwrap();
+        store
+            .create_if_absent("ns", PinItem::new("ABCD".to_string(), None, Utc::now()))
+            .unwrap();
+        drop(store);
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.get("ns", "ABCD").unwrap().is_some());
+    }
+}
@@ -0,0 +1,347 @@
+use super::PinItem;
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Each record is framed as `[len: u32 LE][crc32: u32 LE][len bytes of JSON]`.
+const HEADER_LEN: usize = 8;
+/// Far larger than any legitimate record; anything bigger is a torn length prefix.
+const MAX_RECORD_LEN: u32 = 1024 * 1024;
+
+/// A single mutation of the pin store.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WalRecord {
+    /// A fresh pin.
+    Insert { key: String, item: PinItem },
+    /// A pin whose contents changed, e.g. a submitted result.
+    Update { key: String, item: PinItem },
+    /// A record dropped once its pin and tombstone went stale.
+    Expire { key: String },
+}
+
+/// When appended records are flushed to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// fsync after every record. Nothing acknowledged is ever lost.
+    Always,
+    /// fsync at most once per interval. Up to an interval of writes can be lost on power failure.
+    Interval(std::time::Duration),
+    /// Leave flushing to the OS. Survives process crashes but not power failure.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses `always`, `never`, `interval` (one second) or `interval:<millis>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            "interval" => Ok(FsyncPolicy::Interval(std::time::Duration::from_secs(1))),
+            other => match other.strip_prefix("interval:") {
+                Some(millis) => Ok(FsyncPolicy::Interval(std::time::Duration::from_millis(
+                    millis.parse().context("Invalid fsync interval")?,
+                ))),
+                None => anyhow::bail!("Unknown fsync policy {:?}", s),
+            },
+        }
+    }
+}
+
+/// Append-only journal of store mutations since the last snapshot.
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    last_sync: Instant,
+    unsynced: bool,
+    /// Where the last intact record ends, so a failed append can be cut off.
+    len: u64,
+    /// Set when a failed append could not be cut off. Anything appended after
+    /// it would be lost behind the torn frame on replay, so appends are
+    /// refused until a checkpoint empties the log.
+    failed: bool,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path` and returns every intact record in it.
+    /// A torn record at the tail, left by a crash mid-append, is truncated away.
+    pub fn open(path: &Path, policy: FsyncPolicy) -> anyhow::Result<(Self, Vec<WalRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Could not open write-ahead log {}", path.display()))?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let (records, valid_len) = decode_records(&contents);
+        if valid_len < contents.len() {
+            warn!(
+                "Discarding {} bytes of torn write-ahead log at the end of {}",
+                contents.len() - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let wal = Wal {
+            file,
+            path: path.to_path_buf(),
+            policy,
+            last_sync: Instant::now(),
+            unsynced: false,
+            len: valid_len as u64,
+            failed: false,
+        };
+        Ok((wal, records))
+    }
+
+    /// Appends `record`, or leaves the log as it was and returns an error.
+    pub fn append(&mut self, record: &WalRecord) -> anyhow::Result<()> {
+        if self.failed {
+            anyhow::bail!(
+                "Write-ahead log {} is unusable after a failed write",
+                self.path.display()
+            );
+        }
+        let payload = serde_json::to_vec(record)?;
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        if let Err(e) = self.write_frame(&frame) {
+            // Cut off whatever part of the frame made it, so later records are
+            // not stranded behind it.
+            if let Err(rollback) = self.roll_back() {
+                warn!(
+                    "Could not roll back a failed write to {}, refusing further writes: {}",
+                    self.path.display(),
+                    rollback
+                );
+                self.failed = true;
+            }
+            return Err(e).with_context(|| format!("Could not append to {}", self.path.display()));
+        }
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.file.write_all(frame)?;
+        self.unsynced = true;
+        match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Interval(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn roll_back(&mut self) -> io::Result<()> {
+        self.file.set_len(self.len)?;
+        self.file.seek(SeekFrom::Start(self.len))?;
+        Ok(())
+    }
+
+    /// Flushes outstanding records if the interval policy says they are due.
+    /// Called periodically so a quiet log does not sit unsynced indefinitely.
+    pub fn sync_if_due(&mut self) -> io::Result<()> {
+        match self.policy {
+            FsyncPolicy::Interval(interval)
+                if self.unsynced && self.last_sync.elapsed() >= interval =>
+            {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Empties the log once its contents are covered by a snapshot.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.sync()?;
+        self.len = 0;
+        self.failed = false;
+        Ok(())
+    }
+
+    /// Swaps the file for a read-only handle, so every write to it fails.
+    #[cfg(test)]
+    pub(crate) fn fail_writes(&mut self) -> io::Result<()> {
+        self.file = File::open(&self.path)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Decodes records until the first incomplete or corrupt frame, returning the
+/// records and the length of the valid prefix.
+fn decode_records(contents: &[u8]) -> (Vec<WalRecord>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while contents.len() - offset >= HEADER_LEN {
+        let header = &contents[offset..offset + HEADER_LEN];
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len > MAX_RECORD_LEN {
+            break;
+        }
+        let start = offset + HEADER_LEN;
+        let end = start + len as usize;
+        if end > contents.len() {
+            break;
+        }
+        let payload = &contents[start..end];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        match serde_json::from_slice(payload) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+        offset = end;
+    }
+    (records, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seal::Keyring;
+    use crate::store::create_key;
+    use chrono::prelude::Utc;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn sample_records() -> Vec<WalRecord> {
+        let mut data = HashMap::new();
+        data.insert("ssid".to_string(), json!("home"));
+        vec![
+            WalRecord::Insert {
+                key: create_key("test", "ABCD"),
+                item: PinItem::new("ABCD".to_string(), None, Utc::now()),
+            },
+            WalRecord::Update {
+                key: create_key("test", "ABCD"),
+                item: PinItem::new(
+                    "ABCD".to_string(),
+                    Some(
+                        Keyring::generate()
+                            .seal("test", "ABCD", &data.into())
+                            .unwrap(),
+                    ),
+                    Utc::now(),
+                ),
+            },
+            WalRecord::Expire {
+                key: create_key("test", "ABCD"),
+            },
+        ]
+    }
+
+    fn write_records(path: &Path, records: &[WalRecord]) {
+        let (mut wal, existing) = Wal::open(path, FsyncPolicy::Always).unwrap();
+        assert!(existing.is_empty());
+        for record in records {
+            wal.append(record).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fsync_policy_parsing() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!("Never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        assert_eq!(
+            "interval:250".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Interval(std::time::Duration::from_millis(250))
+        );
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_reopen_replays_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.wal");
+        let records = sample_records();
+        write_records(&path, &records);
+
+        let (_, replayed) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(replayed, records);
+    }
+
+    #[test]
+    fn test_recovery_from_truncation_at_every_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.wal");
+        let records = sample_records();
+        write_records(&path, &records);
+        let full = std::fs::read(&path).unwrap();
+
+        // Byte offsets at which each successive record ends.
+        let mut boundaries = vec![0];
+        for record in &records {
+            let end =
+                boundaries.last().unwrap() + HEADER_LEN + serde_json::to_vec(record).unwrap().len();
+            boundaries.push(end);
+        }
+
+        for cut in 0..full.len() {
+            std::fs::write(&path, &full[..cut]).unwrap();
+            let (mut wal, replayed) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+
+            let complete = boundaries.iter().rposition(|&end| end <= cut).unwrap();
+            assert_eq!(replayed, records[..complete], "cut at {}", cut);
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().len() as usize,
+                boundaries[complete]
+            );
+
+            // The log stays appendable after the torn tail is dropped.
+            wal.append(&records[0]).unwrap();
+            drop(wal);
+            let (_, replayed) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+            assert_eq!(replayed.len(), complete + 1);
+        }
+    }
+
+    #[test]
+    fn test_recovery_stops_at_corrupt_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pins.wal");
+        let records = sample_records();
+        write_records(&path, &records);
+
+        let mut contents = std::fs::read(&path).unwrap();
+        let last = contents.len() - 2;
+        contents[last] ^= 0xFF;
+        std::fs::write(&path, &contents).unwrap();
+
+        let (_, replayed) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(replayed, records[..2]);
+    }
+}
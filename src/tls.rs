@@ -0,0 +1,458 @@
+//! TLS termination with rustls. Certificates are read from PEM files and can
+//! be swapped for new ones while the server runs: each handshake uses the
+//! configuration current when it starts, so connections already open carry
+//! on undisturbed.
+
+use anyhow::Context;
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use log::debug;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Clients that have not finished their handshake by then are dropped, so
+/// they cannot hold connections open.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections handshaken but not yet picked up by the server.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Where the server's certificate and key are, and who may present client
+/// certificates.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsSettings {
+    /// The certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA that client certificates must be issued by. Clients need not
+    /// present one unless their namespace requires it.
+    pub client_ca_path: Option<PathBuf>,
+    /// Namespaces whose requests must come with a client certificate.
+    pub client_cert_namespaces: NamespacePatterns,
+}
+
+/// Comma-separated namespace patterns, where `*` matches anything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamespacePatterns(pub Vec<String>);
+
+impl NamespacePatterns {
+    pub fn matches(&self, namespace: &str) -> bool {
+        self.0
+            .iter()
+            .any(|pattern| crate::auth::matches_pattern(pattern, namespace))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for NamespacePatterns {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(NamespacePatterns(
+            s.split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+}
+
+/// When each file was last changed, to tell when they need reading again.
+type Stamps = Vec<Option<(SystemTime, u64)>>;
+
+/// The server's TLS configuration, reloadable from its files.
+pub struct TlsConfig {
+    settings: TlsSettings,
+    current: RwLock<Arc<ServerConfig>>,
+    stamps: Mutex<Stamps>,
+}
+
+impl TlsConfig {
+    pub fn load(settings: TlsSettings) -> anyhow::Result<Self> {
+        let stamps = stamps(&settings);
+        let config = server_config(&settings)?;
+        Ok(TlsConfig {
+            settings,
+            current: RwLock::new(Arc::new(config)),
+            stamps: Mutex::new(stamps),
+        })
+    }
+
+    /// Reads the files again. New connections use what they hold from now
+    /// on, unless they cannot be used, in which case nothing changes.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        *self.stamps.lock().unwrap_or_else(|p| p.into_inner()) = stamps(&self.settings);
+        let config = server_config(&self.settings)?;
+        *self.current.write().unwrap_or_else(|p| p.into_inner()) = Arc::new(config);
+        Ok(())
+    }
+
+    /// Reloads if any of the files changed since they were last read, saying
+    /// whether they had.
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let unchanged =
+            *self.stamps.lock().unwrap_or_else(|p| p.into_inner()) == stamps(&self.settings);
+        if unchanged {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(
+            self.current
+                .read()
+                .unwrap_or_else(|p| p.into_inner())
+                .clone(),
+        )
+    }
+}
+
+fn stamps(settings: &TlsSettings) -> Stamps {
+    [&settings.cert_path, &settings.key_path]
+        .into_iter()
+        .chain(&settings.client_ca_path)
+        .map(|path| {
+            let metadata = std::fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+fn server_config(settings: &TlsSettings) -> anyhow::Result<ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &settings.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+            }
+            // Whether a client needs a certificate depends on the namespace
+            // it asks for, which is only known once the request arrives.
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let key = PrivateKeyDer::from_pem_file(&settings.key_path)
+        .with_context(|| format!("Could not read a key from {}", settings.key_path.display()))?;
+    let mut config = builder
+        .with_single_cert(read_certs(&settings.cert_path)?, key)
+        .context("The certificate does not match its key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Could not read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Accepts TLS connections for `axum::serve`. Handshakes run concurrently in
+/// the background, so a slow client does not hold up anyone else's.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    handshaken: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(mut listener: TcpListener, config: Arc<TlsConfig>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, handshaken) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    // Retries failed accepts, the way axum does for plain TCP.
+                    accepted = Listener::accept(&mut listener) => accepted,
+                    // The server has stopped listening.
+                    _ = sender.closed() => return,
+                };
+                let acceptor = config.acceptor();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            sender.send((stream, addr)).await.ok();
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            local_addr,
+            handshaken,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Who is at the other end of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Whether the client presented a certificate from the client CA.
+    pub client_certified: bool,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Peer {
+            addr: *stream.remote_addr(),
+            client_certified: false,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        let (_, connection) = stream.io().get_ref();
+        Peer {
+            addr: *stream.remote_addr(),
+            // The verifier has already turned away certificates from anyone else.
+            client_certified: connection.peer_certificates().is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use axum::Router;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use std::fs::File;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::client::TlsStream as ClientTlsStream;
+    use tokio_rustls::TlsConnector;
+
+    struct Pki {
+        ca: CertifiedIssuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            Pki {
+                ca: CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap(),
+            }
+        }
+
+        /// A certificate and key in PEM, for a server or a client.
+        fn issue(&self, purpose: ExtendedKeyUsagePurpose) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+            params.extended_key_usages = vec![purpose];
+            let cert = params.signed_by(&key, &self.ca).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn client_config(&self, identity: Option<(String, String)>) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            let builder = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+            let config = match identity {
+                Some((cert, key)) => builder
+                    .with_client_auth_cert(
+                        vec![CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()],
+                        PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            Arc::new(config)
+        }
+    }
+
+    async fn serve(config: Arc<TlsConfig>) -> SocketAddr {
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<Peer>| async move {
+                format!("certified={}", peer.client_certified)
+            }),
+        );
+        let listener =
+            TlsListener::new(TcpListener::bind("127.0.0.1:0").await.unwrap(), config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<Peer>())
+                .await
+                .unwrap()
+        });
+        addr
+    }
+
+    async fn connect(addr: SocketAddr, client: Arc<ClientConfig>) -> ClientTlsStream<TcpStream> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(client)
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    /// Makes a request on a kept-alive connection, returning the body.
+    async fn get_root(stream: &mut ClientTlsStream<TcpStream>) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+        while !response.ends_with(b"true") && !response.ends_with(b"false") {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "connection closed");
+            response.extend_from_slice(&buffer[..read]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        response.rsplit("\r\n").next().unwrap().to_string()
+    }
+
+    fn served_cert(stream: &ClientTlsStream<TcpStream>) -> CertificateDer<'static> {
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    fn write_identity(settings: &TlsSettings, (cert, key): (String, String)) {
+        std::fs::write(&settings.cert_path, cert).unwrap();
+        std::fs::write(&settings.key_path, key).unwrap();
+        // Renewals happen within the same second in tests, so they are dated later.
+        let later = SystemTime::now() + Duration::from_secs(10);
+        for path in [&settings.cert_path, &settings.key_path] {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(later)
+                .unwrap();
+        }
+    }
+
+    fn settings(dir: &Path, client_ca: Option<&Pki>) -> TlsSettings {
+        let client_ca_path = client_ca.map(|pki| {
+            let path = dir.join("client-ca.pem");
+            std::fs::write(&path, pki.ca.pem()).unwrap();
+            path
+        });
+        TlsSettings {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            client_ca_path,
+            client_cert_namespaces: NamespacePatterns::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificates_are_reloaded_without_dropping_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let pki = Pki::new();
+        let settings = settings(dir.path(), None);
+        write_identity(&settings, pki.issue(ExtendedKeyUsagePurpose::ServerAuth));
+        let config = Arc::new(TlsConfig::load(settings.clone()).unwrap());
+        assert!(!config.reload_if_changed().unwrap());
+        let addr = serve(config.clone()).await;
+
+        let mut before = connect(addr, pki.client_config(None)).await;
+        assert_eq!(get_root(&mut before).await, "certified=false");
+        let old_cert = served_cert(&before);
+
+        write_identity(&settings, pki.issue(ExtendedKeyUsagePurpose::ServerAuth));
+        assert!(config.reload_if_changed().unwrap());
+        let mut after = connect(addr, pki.client_config(None)).await;
+        assert_ne!(served_cert(&after), old_cert);
+        assert_eq!(get_root(&mut after).await, "certified=false");
+        // The connection from before the reload is still being served.
+        assert_eq!(get_root(&mut before).await, "certified=false");
+
+        // A broken renewal leaves the last good certificate in place.
+        std::fs::write(&settings.key_path, "not a key").unwrap();
+        assert!(config.reload().is_err());
+        let mut again = connect(addr, pki.client_config(None)).await;
+        assert_eq!(served_cert(&again), served_cert(&after));
+        assert_eq!(get_root(&mut again).await, "certified=false");
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates_are_optional_but_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let pki = Pki::new();
+        let settings = settings(dir.path(), Some(&pki));
+        write_identity(&settings, pki.issue(ExtendedKeyUsagePurpose::ServerAuth));
+        let addr = serve(Arc::new(TlsConfig::load(settings).unwrap())).await;
+
+        let mut anonymous = connect(addr, pki.client_config(None)).await;
+        assert_eq!(get_root(&mut anonymous).await, "certified=false");
+        let identity = pki.issue(ExtendedKeyUsagePurpose::ClientAuth);
+        let mut certified = connect(addr, pki.client_config(Some(identity))).await;
+        assert_eq!(get_root(&mut certified).await, "certified=true");
+
+        // A certificate from another CA does not get the client in.
+        let stranger = Pki::new().issue(ExtendedKeyUsagePurpose::ClientAuth);
+        let mut refused = connect(addr, pki.client_config(Some(stranger))).await;
+        let mut buffer = [0; 16];
+        refused.write_all(b"GET / HTTP/1.1\r\n\r\n").await.ok();
+        assert!(!matches!(refused.read(&mut buffer).await, Ok(n) if n > 0));
+    }
+
+    #[test]
+    fn test_namespace_patterns() {
+        let patterns: NamespacePatterns = " payments, tv-* ,".parse().unwrap();
+        assert_eq!(patterns.0, ["payments", "tv-*"]);
+        assert!(patterns.matches("tv-lounge"));
+        assert!(!patterns.matches("kitchen"));
+        assert!(NamespacePatterns::default().is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+//! Bearer secrets handed out with a pin. Only their hashes are stored, so a
+//! leaked snapshot or database cannot be used to act on live pins.
+
+use rand::distr::Alphanumeric;
+use rand::{rng, Rng};
+use sha2::{Digest, Sha256};
+
+const TOKEN_LENGTH: usize = 32;
+
+/// A fresh random token.
+pub fn generate() -> String {
+    rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// What gets stored in place of `token`.
+pub fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Whether `token` is the one `stored` was hashed from.
+pub fn matches(token: &str, stored: &str) -> bool {
+    hash(token) == stored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_match_only_their_own_hash() {
+        let token = generate();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_ne!(token, generate());
+
+        let stored = hash(&token);
+        assert_ne!(stored, token);
+        assert!(matches(&token, &stored));
+        assert!(!matches(&generate(), &stored));
+        assert!(!matches("", &stored));
+    }
+}
@@ -0,0 +1,110 @@
+//! Lets long polls and event streams sleep until their pin changes, woken by
+//! the request or expiry that changed it rather than by polling the store.
+
+use crate::store::create_key;
+use dashmap::DashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// One `Notify` per pin that somebody is currently waiting on.
+#[derive(Default)]
+pub struct Waiters {
+    pins: DashMap<String, Arc<Notify>>,
+}
+
+impl Waiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts listening for changes to a pin. Subscribe before checking the
+    /// store, so a change that lands in between is not missed.
+    pub fn subscribe(self: &Arc<Self>, namespace: &str, pin: &str) -> Subscription {
+        let key = create_key(namespace, pin);
+        let notify = self.pins.entry(key.clone()).or_default().clone();
+        Subscription {
+            waiters: self.clone(),
+            key,
+            notify,
+        }
+    }
+
+    /// Wakes everyone waiting on the pin, to look at it again.
+    pub fn notify(&self, namespace: &str, pin: &str) {
+        if let Some(notify) = self.pins.get(&create_key(namespace, pin)) {
+            notify.notify_waiters();
+        }
+    }
+
+    /// Number of pins with someone waiting on them.
+    pub fn len(&self) -> usize {
+        self.pins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+}
+
+pub struct Subscription {
+    waiters: Arc<Waiters>,
+    key: String,
+    notify: Arc<Notify>,
+}
+
+impl Subscription {
+    /// A future that completes on the next [`Waiters::notify`] for the pin,
+    /// counting from when this is called rather than when it is first polled.
+    pub fn changed(&self) -> Pin<Box<Notified<'_>>> {
+        let mut notified = Box::pin(self.notify.notified());
+        notified.as_mut().enable();
+        notified
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // The map holds one reference and we hold another; anyone else still waiting holds a third.
+        self.waiters
+            .pins
+            .remove_if(&self.key, |_, notify| Arc::strong_count(notify) <= 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_notify_wakes_subscribers_of_that_pin_only() {
+        let waiters = Arc::new(Waiters::new());
+        let subscription = waiters.subscribe("ns", "ABCD");
+        let other = waiters.subscribe("ns", "WXYZ");
+        let changed = subscription.changed();
+        let unchanged = other.changed();
+
+        // Notified before anyone awaits, which must not be lost.
+        waiters.notify("ns", "ABCD");
+        assert!(timeout(Duration::from_secs(5), changed).await.is_ok());
+        assert!(timeout(Duration::from_secs(5), unchanged).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_last_subscription_cleans_up() {
+        let waiters = Arc::new(Waiters::new());
+        let first = waiters.subscribe("ns", "ABCD");
+        let second = waiters.subscribe("ns", "ABCD");
+        assert_eq!(waiters.len(), 1);
+
+        drop(first);
+        assert_eq!(waiters.len(), 1);
+        drop(second);
+        assert!(waiters.is_empty());
+        // Nobody is listening, so this is a no-op.
+        waiters.notify("ns", "ABCD");
+    }
+}